@@ -0,0 +1,402 @@
+use candid::{CandidType, Deserialize, Int, Nat};
+use ic_ethereum_types::Address;
+use num::bigint::Sign;
+use num::{BigInt, BigUint, One};
+use serde_bytes::ByteBuf;
+use std::str::FromStr;
+
+/// Size of an ABI word in bytes.
+const WORD_SIZE: usize = 32;
+
+/// A value that can be passed to or returned from a contract function.
+/// Only the elementary types and dynamic `bytes`/`string` are supported.
+#[derive(CandidType, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub enum AbiValue {
+    Address(String),
+    Bool(bool),
+    Uint(Nat),
+    Int(Int),
+    FixedBytes(ByteBuf),
+    Bytes(ByteBuf),
+    String(String),
+}
+
+/// The ABI type of a single function parameter.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum AbiType {
+    Address,
+    Bool,
+    Uint(usize),
+    Int(usize),
+    FixedBytes(usize),
+    Bytes,
+    String,
+}
+
+impl AbiType {
+    fn is_dynamic(&self) -> bool {
+        matches!(self, AbiType::Bytes | AbiType::String)
+    }
+}
+
+impl FromStr for AbiType {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let bits = |digits: &str| -> Result<usize, String> {
+            if digits.is_empty() {
+                return Ok(256);
+            }
+            match digits.parse::<usize>() {
+                Ok(bits) if bits > 0 && bits <= 256 && bits % 8 == 0 => Ok(bits),
+                _ => Err(format!("invalid ABI type {}", s)),
+            }
+        };
+        match s {
+            "address" => Ok(AbiType::Address),
+            "bool" => Ok(AbiType::Bool),
+            "bytes" => Ok(AbiType::Bytes),
+            "string" => Ok(AbiType::String),
+            _ => {
+                if let Some(digits) = s.strip_prefix("uint") {
+                    bits(digits).map(AbiType::Uint)
+                } else if let Some(digits) = s.strip_prefix("int") {
+                    bits(digits).map(AbiType::Int)
+                } else if let Some(digits) = s.strip_prefix("bytes") {
+                    match digits.parse::<usize>() {
+                        Ok(len) if len > 0 && len <= WORD_SIZE => Ok(AbiType::FixedBytes(len)),
+                        _ => Err(format!("invalid ABI type {}", s)),
+                    }
+                } else {
+                    Err(format!("unsupported ABI type {}", s))
+                }
+            }
+        }
+    }
+}
+
+/// A parsed function signature such as `balanceOf(address)(uint256)`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct FunctionSignature {
+    name: String,
+    inputs: Vec<AbiType>,
+    outputs: Vec<AbiType>,
+}
+
+impl FromStr for FunctionSignature {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s: String = s.chars().filter(|c| !c.is_whitespace()).collect();
+        let (name, rest) = s
+            .split_once('(')
+            .ok_or_else(|| format!("missing parameter list in signature {}", s))?;
+        if name.is_empty() {
+            return Err(format!("missing function name in signature {}", s));
+        }
+        let (inputs, rest) = rest
+            .split_once(')')
+            .ok_or_else(|| format!("unbalanced parentheses in signature {}", s))?;
+        let outputs = match rest {
+            "" => "",
+            _ => rest
+                .strip_prefix('(')
+                .and_then(|rest| rest.strip_suffix(')'))
+                .ok_or_else(|| format!("invalid return types in signature {}", s))?,
+        };
+        let parse_types = |types: &str| -> Result<Vec<AbiType>, String> {
+            if types.is_empty() {
+                return Ok(vec![]);
+            }
+            types.split(',').map(AbiType::from_str).collect()
+        };
+        Ok(FunctionSignature {
+            name: name.to_string(),
+            inputs: parse_types(inputs)?,
+            outputs: parse_types(outputs)?,
+        })
+    }
+}
+
+impl FunctionSignature {
+    // -------------------------------------------------------------------------
+    // Compute the 4-byte selector: the first bytes of the Keccak256 hash of the
+    // canonical signature, e.g. `transfer(address,uint256)`.
+    // -------------------------------------------------------------------------
+    pub fn selector(&self) -> [u8; 4] {
+        let canonical = format!(
+            "{}({})",
+            self.name,
+            self.inputs
+                .iter()
+                .map(|t| t.to_string())
+                .collect::<Vec<_>>()
+                .join(",")
+        );
+        let hash = ic_sha3::Keccak256::hash(canonical.as_bytes());
+        [hash[0], hash[1], hash[2], hash[3]]
+    }
+
+    // -------------------------------------------------------------------------
+    // ABI-encode a call: the selector followed by the head and tail of the arguments.
+    // -------------------------------------------------------------------------
+    pub fn encode_call(&self, args: &[AbiValue]) -> Result<Vec<u8>, String> {
+        if args.len() != self.inputs.len() {
+            return Err(format!(
+                "expected {} arguments but got {}",
+                self.inputs.len(),
+                args.len()
+            ));
+        }
+        let head_size = self.inputs.len() * WORD_SIZE;
+        let mut head = Vec::with_capacity(head_size);
+        let mut tail = Vec::new();
+        for (abi_type, arg) in self.inputs.iter().zip(args) {
+            if abi_type.is_dynamic() {
+                head.extend_from_slice(&encode_usize(head_size + tail.len()));
+                tail.extend(encode_dynamic(abi_type, arg)?);
+            } else {
+                head.extend_from_slice(&encode_static(abi_type, arg)?);
+            }
+        }
+        let mut calldata = self.selector().to_vec();
+        calldata.extend(head);
+        calldata.extend(tail);
+        Ok(calldata)
+    }
+
+    // -------------------------------------------------------------------------
+    // Decode the data returned by a call according to the declared return types.
+    // -------------------------------------------------------------------------
+    pub fn decode_output(&self, data: &[u8]) -> Result<Vec<AbiValue>, String> {
+        self.outputs
+            .iter()
+            .enumerate()
+            .map(|(index, abi_type)| {
+                let word = read_word(data, index * WORD_SIZE)?;
+                if abi_type.is_dynamic() {
+                    let offset = word_to_usize(word)?;
+                    let len = word_to_usize(read_word(data, offset)?)?;
+                    let start = offset + WORD_SIZE;
+                    let bytes = data
+                        .get(start..start.saturating_add(len))
+                        .ok_or_else(|| format!("return data too short for {}", abi_type))?;
+                    decode_dynamic(abi_type, bytes)
+                } else {
+                    decode_static(abi_type, word)
+                }
+            })
+            .collect()
+    }
+}
+
+impl std::fmt::Display for AbiType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AbiType::Address => write!(f, "address"),
+            AbiType::Bool => write!(f, "bool"),
+            AbiType::Uint(bits) => write!(f, "uint{}", bits),
+            AbiType::Int(bits) => write!(f, "int{}", bits),
+            AbiType::FixedBytes(len) => write!(f, "bytes{}", len),
+            AbiType::Bytes => write!(f, "bytes"),
+            AbiType::String => write!(f, "string"),
+        }
+    }
+}
+
+fn encode_static(abi_type: &AbiType, value: &AbiValue) -> Result<[u8; WORD_SIZE], String> {
+    let mut word = [0u8; WORD_SIZE];
+    match (abi_type, value) {
+        (AbiType::Address, AbiValue::Address(address)) => {
+            let address = Address::from_str(address)
+                .map_err(|e| format!("invalid address {}: {:?}", address, e))?;
+            word[12..].copy_from_slice(&address.into_bytes());
+        }
+        (AbiType::Bool, AbiValue::Bool(value)) => word[31] = *value as u8,
+        (AbiType::Uint(bits), AbiValue::Uint(value)) => {
+            if value.0.bits() > *bits as u64 {
+                return Err(format!("{} does not fit into uint{}", value, bits));
+            }
+            let bytes = value.0.to_bytes_be();
+            word[WORD_SIZE - bytes.len()..].copy_from_slice(&bytes);
+        }
+        (AbiType::Int(bits), AbiValue::Int(value)) => {
+            let bound = BigInt::one() << (*bits - 1);
+            if value.0 >= bound || value.0 < -bound {
+                return Err(format!("{} does not fit into int{}", value, bits));
+            }
+            // Two's complement representation on 256 bits.
+            let modulus = BigInt::one() << (WORD_SIZE * 8);
+            let unsigned = if value.0.sign() == Sign::Minus {
+                &modulus + &value.0
+            } else {
+                value.0.clone()
+            };
+            let (_, bytes) = unsigned.to_bytes_be();
+            word[WORD_SIZE - bytes.len()..].copy_from_slice(&bytes);
+        }
+        (AbiType::FixedBytes(len), AbiValue::FixedBytes(bytes)) => {
+            if bytes.len() != *len {
+                return Err(format!("expected {} bytes but got {}", len, bytes.len()));
+            }
+            word[..*len].copy_from_slice(bytes);
+        }
+        _ => return Err(format!("value {:?} does not match type {}", value, abi_type)),
+    }
+    Ok(word)
+}
+
+fn encode_dynamic(abi_type: &AbiType, value: &AbiValue) -> Result<Vec<u8>, String> {
+    let bytes: &[u8] = match (abi_type, value) {
+        (AbiType::Bytes, AbiValue::Bytes(bytes)) => bytes,
+        (AbiType::String, AbiValue::String(string)) => string.as_bytes(),
+        _ => return Err(format!("value {:?} does not match type {}", value, abi_type)),
+    };
+    let mut encoded = encode_usize(bytes.len()).to_vec();
+    encoded.extend_from_slice(bytes);
+    // Right-pad the data to a multiple of the word size.
+    encoded.resize(WORD_SIZE + bytes.len().div_ceil(WORD_SIZE) * WORD_SIZE, 0);
+    Ok(encoded)
+}
+
+fn decode_static(abi_type: &AbiType, word: &[u8]) -> Result<AbiValue, String> {
+    match abi_type {
+        AbiType::Address => {
+            let mut address = [0u8; 20];
+            address.copy_from_slice(&word[12..]);
+            Ok(AbiValue::Address(Address::new(address).to_string()))
+        }
+        AbiType::Bool => Ok(AbiValue::Bool(word[31] != 0)),
+        AbiType::Uint(_) => Ok(AbiValue::Uint(Nat(BigUint::from_bytes_be(word)))),
+        AbiType::Int(_) => {
+            let unsigned = BigInt::from_bytes_be(Sign::Plus, word);
+            let value = if word[0] & 0x80 != 0 {
+                unsigned - (BigInt::one() << (WORD_SIZE * 8))
+            } else {
+                unsigned
+            };
+            Ok(AbiValue::Int(Int(value)))
+        }
+        AbiType::FixedBytes(len) => Ok(AbiValue::FixedBytes(ByteBuf::from(word[..*len].to_vec()))),
+        AbiType::Bytes | AbiType::String => Err(format!("{} is not a static type", abi_type)),
+    }
+}
+
+fn decode_dynamic(abi_type: &AbiType, bytes: &[u8]) -> Result<AbiValue, String> {
+    match abi_type {
+        AbiType::Bytes => Ok(AbiValue::Bytes(ByteBuf::from(bytes.to_vec()))),
+        AbiType::String => String::from_utf8(bytes.to_vec())
+            .map(AbiValue::String)
+            .map_err(|e| format!("invalid UTF-8 string: {}", e)),
+        _ => Err(format!("{} is not a dynamic type", abi_type)),
+    }
+}
+
+fn encode_usize(value: usize) -> [u8; WORD_SIZE] {
+    let mut word = [0u8; WORD_SIZE];
+    word[WORD_SIZE - 8..].copy_from_slice(&(value as u64).to_be_bytes());
+    word
+}
+
+fn read_word(data: &[u8], offset: usize) -> Result<&[u8], String> {
+    data.get(offset..offset.saturating_add(WORD_SIZE))
+        .ok_or_else(|| format!("return data too short to read a word at offset {}", offset))
+}
+
+fn word_to_usize(word: &[u8]) -> Result<usize, String> {
+    usize::try_from(BigUint::from_bytes_be(word)).map_err(|_| "offset or length does not fit into usize".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn signature(s: &str) -> FunctionSignature {
+        FunctionSignature::from_str(s).expect("valid signature")
+    }
+
+    fn word(value: u8) -> [u8; WORD_SIZE] {
+        let mut word = [0u8; WORD_SIZE];
+        word[WORD_SIZE - 1] = value;
+        word
+    }
+
+    #[test]
+    fn should_compute_balance_of_selector() {
+        assert_eq!(
+            signature("balanceOf(address)(uint256)").selector(),
+            [0x70, 0xa0, 0x82, 0x31]
+        );
+    }
+
+    #[test]
+    fn should_canonicalize_signature() {
+        let canonical = signature("transfer(address,uint256)(bool)");
+        let aliased = signature("transfer( address, uint )( bool )");
+        assert_eq!(aliased, canonical);
+        assert_eq!(aliased.selector(), [0xa9, 0x05, 0x9c, 0xbb]);
+    }
+
+    #[test]
+    fn should_encode_and_decode_negative_int8() {
+        let signature = signature("f(int8)(int8)");
+        let calldata = signature
+            .encode_call(&[AbiValue::Int(Int::from(-1))])
+            .unwrap();
+        assert_eq!(&calldata[4..], &[0xff; WORD_SIZE]);
+        assert_eq!(
+            signature.decode_output(&calldata[4..]),
+            Ok(vec![AbiValue::Int(Int::from(-1))])
+        );
+        assert!(signature
+            .encode_call(&[AbiValue::Int(Int::from(-129))])
+            .is_err());
+        assert!(signature
+            .encode_call(&[AbiValue::Int(Int::from(128))])
+            .is_err());
+    }
+
+    #[test]
+    fn should_encode_and_decode_min_int256() {
+        let signature = signature("f(int256)(int256)");
+        let min = AbiValue::Int(Int(-(BigInt::one() << 255)));
+        let calldata = signature.encode_call(&[min.clone()]).unwrap();
+        let mut expected = [0u8; WORD_SIZE];
+        expected[0] = 0x80;
+        assert_eq!(&calldata[4..], &expected);
+        assert_eq!(signature.decode_output(&calldata[4..]), Ok(vec![min]));
+    }
+
+    #[test]
+    fn should_decode_string_return() {
+        let mut data = word(0x20).to_vec();
+        data.extend_from_slice(&word(5));
+        let mut padded = b"hello".to_vec();
+        padded.resize(WORD_SIZE, 0);
+        data.extend(padded);
+        assert_eq!(
+            signature("name()(string)").decode_output(&data),
+            Ok(vec![AbiValue::String("hello".to_string())])
+        );
+    }
+
+    #[test]
+    fn should_return_error_on_truncated_return_data() {
+        assert!(signature("balanceOf(address)(uint256)")
+            .decode_output(&[0u8; 16])
+            .is_err());
+
+        let name = signature("name()(string)");
+        // Offset pointing past the end of the data.
+        assert!(name.decode_output(&word(0x40)).is_err());
+        // Length larger than the remaining data.
+        let mut data = word(0x20).to_vec();
+        data.extend_from_slice(&word(0x40));
+        data.extend_from_slice(b"hello");
+        assert!(name.decode_output(&data).is_err());
+        // Length that does not fit into usize.
+        let mut data = word(0x20).to_vec();
+        data.extend_from_slice(&[0xff; WORD_SIZE]);
+        assert!(name.decode_output(&data).is_err());
+    }
+}
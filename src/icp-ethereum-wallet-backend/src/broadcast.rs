@@ -0,0 +1,289 @@
+use crate::error::WalletError;
+use crate::state::{mutate_state, read_state};
+use crate::{BroadcastStrategy, ChainConfig, EVM_RPC};
+use evm_rpc_canister_types::{
+    MultiSendRawTransactionResult, RpcService, SendRawTransactionResult,
+    SendRawTransactionStatus,
+};
+
+/// Error messages returned by nodes that already have the transaction in their mempool.
+const ALREADY_KNOWN_ERRORS: [&str; 3] = ["already known", "known transaction", "already imported"];
+
+// -----------------------------------------------------------------------------
+// Send a signed transaction to the RPC providers of the chain.
+// The transaction is signed, so a malicious provider can only affect availability:
+// the broadcast succeeds as soon as one provider accepts the transaction, and
+// only fails if all of them reject it.
+// -----------------------------------------------------------------------------
+pub async fn broadcast_raw_transaction(
+    chain: &ChainConfig,
+    raw_transaction_hex: &str,
+) -> Result<(), WalletError> {
+    let rpc_services = chain.json_rpc_services();
+    let results = match chain.broadcast_strategy() {
+        BroadcastStrategy::RoundRobin => {
+            // Start with a different provider for each transaction to spread the load.
+            let start = mutate_state(|s| s.next_broadcast_offset()) % rpc_services.len().max(1);
+            let mut results = Vec::with_capacity(rpc_services.len());
+            for rpc_service in rpc_services.iter().cycle().skip(start).take(rpc_services.len()) {
+                let result =
+                    send_raw_transaction(chain, vec![rpc_service.clone()], raw_transaction_hex)
+                        .await;
+                let accepted = result.iter().any(|(_, outcome)| outcome.is_ok());
+                results.extend(result);
+                if accepted {
+                    break;
+                }
+            }
+            results
+        }
+        BroadcastStrategy::AllAtOnce => {
+            send_raw_transaction(chain, rpc_services, raw_transaction_hex).await
+        }
+    };
+    ic_cdk::println!(
+        "Result of broadcasting raw transaction {}: {:?}",
+        raw_transaction_hex,
+        results
+    );
+    reduce_broadcast_results(results)
+}
+
+// Send the transaction through the given providers in a single call to the EVM RPC canister
+// and classify the outcome reported by each provider.
+async fn send_raw_transaction(
+    chain: &ChainConfig,
+    rpc_services: Vec<RpcService>,
+    raw_transaction_hex: &str,
+) -> Vec<(Option<RpcService>, Result<(), WalletError>)> {
+    let evm_rpc_cycles = read_state(|s| s.evm_rpc_cycles());
+    let result = EVM_RPC
+        .eth_send_raw_transaction(
+            chain.evm_rpc_services_of(rpc_services.clone()),
+            None,
+            raw_transaction_hex.to_string(),
+            evm_rpc_cycles,
+        )
+        .await;
+    match result {
+        Ok((MultiSendRawTransactionResult::Consistent(result),)) => {
+            vec![(None, classify_send_result(&result))]
+        }
+        Ok((MultiSendRawTransactionResult::Inconsistent(results),)) => results
+            .into_iter()
+            .map(|(rpc_service, result)| (Some(rpc_service), classify_send_result(&result)))
+            .collect(),
+        Err(e) => vec![(
+            None,
+            Err(WalletError::RpcError(format!(
+                "failed to send raw transaction through {:?}, error: {:?}",
+                rpc_services, e
+            ))),
+        )],
+    }
+}
+
+fn classify_send_result(result: &SendRawTransactionResult) -> Result<(), WalletError> {
+    match result {
+        SendRawTransactionResult::Ok(SendRawTransactionStatus::Ok(_)) => Ok(()),
+        SendRawTransactionResult::Ok(SendRawTransactionStatus::NonceTooLow) => {
+            Err(WalletError::NonceTooLow)
+        }
+        SendRawTransactionResult::Ok(SendRawTransactionStatus::NonceTooHigh) => {
+            Err(WalletError::NonceTooHigh)
+        }
+        SendRawTransactionResult::Ok(SendRawTransactionStatus::InsufficientFunds) => {
+            Err(WalletError::InsufficientFunds)
+        }
+        SendRawTransactionResult::Err(error) => {
+            let message = format!("{:?}", error);
+            let lowercase_message = message.to_lowercase();
+            if ALREADY_KNOWN_ERRORS
+                .iter()
+                .any(|known| lowercase_message.contains(known))
+            {
+                // The provider already has the transaction, e.g. from an earlier broadcast.
+                Ok(())
+            } else {
+                Err(WalletError::RpcError(format!(
+                    "failed to send raw transaction, error: {}",
+                    message
+                )))
+            }
+        }
+    }
+}
+
+// Succeed if any provider accepted the transaction. Otherwise, prefer a rejection of
+// the transaction itself (e.g. insufficient funds) over transport or provider errors.
+// A nonce rejected by some providers only may be caused by lagging nodes and is only
+// reported when every provider rejected it.
+fn reduce_broadcast_results(
+    results: Vec<(Option<RpcService>, Result<(), WalletError>)>,
+) -> Result<(), WalletError> {
+    if results.iter().any(|(_, result)| result.is_ok()) {
+        return Ok(());
+    }
+    let errors: Vec<WalletError> = results
+        .into_iter()
+        .filter_map(|(_, result)| result.err())
+        .collect();
+    if errors.contains(&WalletError::InsufficientFunds) {
+        return Err(WalletError::InsufficientFunds);
+    }
+    for rejection in [WalletError::NonceTooLow, WalletError::NonceTooHigh] {
+        if !errors.is_empty() && errors.iter().all(|error| *error == rejection) {
+            return Err(rejection);
+        }
+    }
+    Err(WalletError::RpcError(format!(
+        "all RPC providers failed to send the transaction: {:?}",
+        errors
+    )))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use evm_rpc_canister_types::{EthMainnetService, JsonRpcError, RpcError};
+
+    fn json_rpc_error(message: &str) -> SendRawTransactionResult {
+        SendRawTransactionResult::Err(RpcError::JsonRpcError(JsonRpcError {
+            code: -32000,
+            message: message.to_string(),
+        }))
+    }
+
+    fn provider(service: EthMainnetService) -> Option<RpcService> {
+        Some(RpcService::EthMainnet(service))
+    }
+
+    #[test]
+    fn should_classify_already_known_as_success() {
+        for message in [
+            "already known",
+            "Known transaction: 0x1234",
+            "Transaction Already Imported",
+        ] {
+            assert_eq!(classify_send_result(&json_rpc_error(message)), Ok(()));
+        }
+    }
+
+    #[test]
+    fn should_classify_send_statuses() {
+        assert_eq!(
+            classify_send_result(&SendRawTransactionResult::Ok(SendRawTransactionStatus::Ok(
+                None
+            ))),
+            Ok(())
+        );
+        assert_eq!(
+            classify_send_result(&SendRawTransactionResult::Ok(
+                SendRawTransactionStatus::NonceTooLow
+            )),
+            Err(WalletError::NonceTooLow)
+        );
+        assert_eq!(
+            classify_send_result(&SendRawTransactionResult::Ok(
+                SendRawTransactionStatus::InsufficientFunds
+            )),
+            Err(WalletError::InsufficientFunds)
+        );
+        assert!(matches!(
+            classify_send_result(&json_rpc_error("internal error")),
+            Err(WalletError::RpcError(_))
+        ));
+    }
+
+    #[test]
+    fn should_succeed_if_any_provider_accepted() {
+        let results = vec![
+            (
+                provider(EthMainnetService::Ankr),
+                Err(WalletError::RpcError("timeout".to_string())),
+            ),
+            (provider(EthMainnetService::PublicNode), Ok(())),
+            (
+                provider(EthMainnetService::Cloudflare),
+                Err(WalletError::InsufficientFunds),
+            ),
+        ];
+        assert_eq!(reduce_broadcast_results(results), Ok(()));
+    }
+
+    #[test]
+    fn should_prefer_insufficient_funds_over_transport_errors() {
+        let results = vec![
+            (
+                provider(EthMainnetService::Ankr),
+                Err(WalletError::RpcError("timeout".to_string())),
+            ),
+            (
+                provider(EthMainnetService::PublicNode),
+                Err(WalletError::InsufficientFunds),
+            ),
+            (
+                provider(EthMainnetService::Cloudflare),
+                Err(WalletError::RpcError("connection refused".to_string())),
+            ),
+        ];
+        assert_eq!(
+            reduce_broadcast_results(results),
+            Err(WalletError::InsufficientFunds)
+        );
+    }
+
+    #[test]
+    fn should_report_nonce_too_low_if_all_providers_rejected_it() {
+        let results = vec![
+            (
+                provider(EthMainnetService::Ankr),
+                Err(WalletError::NonceTooLow),
+            ),
+            (
+                provider(EthMainnetService::PublicNode),
+                Err(WalletError::NonceTooLow),
+            ),
+        ];
+        assert_eq!(
+            reduce_broadcast_results(results),
+            Err(WalletError::NonceTooLow)
+        );
+    }
+
+    #[test]
+    fn should_not_report_nonce_too_low_if_some_providers_failed_otherwise() {
+        let results = vec![
+            (
+                provider(EthMainnetService::Ankr),
+                Err(WalletError::NonceTooLow),
+            ),
+            (
+                provider(EthMainnetService::PublicNode),
+                Err(WalletError::RpcError("timeout".to_string())),
+            ),
+        ];
+        assert!(matches!(
+            reduce_broadcast_results(results),
+            Err(WalletError::RpcError(_))
+        ));
+    }
+
+    #[test]
+    fn should_fail_with_rpc_error_if_all_providers_failed() {
+        let results = vec![
+            (
+                provider(EthMainnetService::Ankr),
+                Err(WalletError::RpcError("timeout".to_string())),
+            ),
+            (
+                provider(EthMainnetService::PublicNode),
+                Err(WalletError::RpcError("connection refused".to_string())),
+            ),
+        ];
+        assert!(matches!(
+            reduce_broadcast_results(results),
+            Err(WalletError::RpcError(_))
+        ));
+    }
+}
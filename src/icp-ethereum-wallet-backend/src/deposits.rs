@@ -0,0 +1,689 @@
+use crate::erc20::{decode_uint256, encode_address_topic, TRANSFER_EVENT_TOPIC};
+use crate::error::WalletError;
+use crate::guard::{TimerGuard, TimerTask};
+use crate::notifications;
+use crate::rpc::{self, parse_hex_quantity};
+use crate::state::{mutate_state, read_state};
+use crate::storage::{credited_transfers_memory, deposit_counts_memory, deposits_memory, VMem};
+use crate::{get_balance_at, get_block, nat_to_u64, parse_address, ChainConfig};
+use alloy_primitives::hex;
+use candid::{CandidType, Decode, Deserialize, Encode, Nat, Principal};
+use evm_rpc_canister_types::BlockTag;
+use ic_ethereum_types::Address;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// How often the registered addresses are scanned for deposits.
+pub const DEPOSIT_SCAN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Maximum number of deposits returned by a single `get_deposits` call.
+pub const MAX_DEPOSITS_PAGE_SIZE: u64 = 100;
+
+/// Maximum number of addresses scanned for deposits on each chain, since every scan
+/// of a chain costs cycles for each of its addresses.
+pub const MAX_DEPOSIT_ACCOUNTS_PER_CHAIN: usize = 100;
+
+/// Maximum number of blocks whose logs are fetched in a single scan of a chain.
+const MAX_BLOCKS_PER_SCAN: u64 = 500;
+
+/// Maximum size of an `eth_getLogs` response.
+const MAX_LOGS_RESPONSE_SIZE_BYTES: u64 = 1_000_000;
+
+/// Errors returned when an `eth_getLogs` response exceeds `MAX_LOGS_RESPONSE_SIZE_BYTES`
+/// or the limits of the provider, which are avoided by scanning fewer blocks.
+const TOO_MANY_LOGS_ERRORS: [&str; 5] = [
+    "size limit",
+    "response size",
+    "more than",
+    "too many",
+    "block range",
+];
+
+/// The address of a principal scanned for deposits on a chain.
+#[derive(CandidType, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct DepositAccount {
+    pub owner: Principal,
+    pub chain_id: u64,
+    /// Ethereum address of the owner's wallet.
+    pub address: String,
+    /// ETH balance at the last scanned block, unknown until the first scan.
+    pub eth_balance: Option<Nat>,
+}
+
+/// Whether the block of a deposit can still be reorged.
+#[derive(CandidType, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DepositStatus {
+    /// The block has the configured number of confirmations but is not final yet.
+    Confirmed,
+    /// The block is final (see `FinalityTag`).
+    Finalized,
+    /// The block was reorged out of the canonical chain before becoming final.
+    /// If the transfer was included again in another block, it is credited as a new deposit.
+    Reverted,
+}
+
+/// A deposit credited to a principal.
+#[derive(CandidType, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct Deposit {
+    pub chain_id: u64,
+    /// Address of the ERC-20 token contract, or `None` for ETH.
+    pub token: Option<String>,
+    pub amount: Nat,
+    /// Block of the token transfer. ETH deposits are detected from balance changes
+    /// and happened at the latest in this block.
+    pub block_number: u64,
+    pub block_hash: String,
+    /// Transaction and log of the token transfer, unknown for ETH deposits.
+    pub tx_hash: Option<String>,
+    pub log_index: Option<u64>,
+    /// Time at which the deposit was credited, in nanoseconds since the epoch.
+    pub timestamp: u64,
+    pub status: DepositStatus,
+}
+
+impl Storable for Deposit {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).expect("BUG: failed to encode deposit"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("BUG: failed to decode deposit")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+/// A token transfer credited as a deposit.
+#[derive(CandidType, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+struct CreditedTransfer {
+    chain_id: u64,
+    tx_hash: String,
+    log_index: u64,
+}
+
+impl CreditedTransfer {
+    /// Return the transfer of a token deposit, or `None` for ETH deposits.
+    fn of(deposit: &Deposit) -> Option<Self> {
+        Some(Self {
+            chain_id: deposit.chain_id,
+            tx_hash: deposit.tx_hash.as_ref()?.to_lowercase(),
+            log_index: deposit.log_index?,
+        })
+    }
+}
+
+impl Storable for CreditedTransfer {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).expect("BUG: failed to encode credited transfer"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("BUG: failed to decode credited transfer")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    /// Deposits of each principal, keyed by owner and by the position of
+    /// the deposit in the owner's deposits.
+    static DEPOSITS: RefCell<StableBTreeMap<(Principal, u64), Deposit, VMem>> =
+        RefCell::new(StableBTreeMap::init(deposits_memory()));
+
+    /// Number of deposits of each principal.
+    static DEPOSIT_COUNTS: RefCell<StableBTreeMap<Principal, u64, VMem>> =
+        RefCell::new(StableBTreeMap::init(deposit_counts_memory()));
+
+    /// Owner and position of the deposit of each token transfer that was not reverted.
+    static CREDITED_TRANSFERS: RefCell<StableBTreeMap<CreditedTransfer, (Principal, u64), VMem>> =
+        RefCell::new(StableBTreeMap::init(credited_transfers_memory()));
+}
+
+/// Index the token transfers of the deposits credited before they were indexed.
+pub fn index_credited_transfers() {
+    if !CREDITED_TRANSFERS.with(|transfers| transfers.borrow().is_empty()) {
+        return;
+    }
+    DEPOSITS.with(|deposits| {
+        CREDITED_TRANSFERS.with(|transfers| {
+            let mut transfers = transfers.borrow_mut();
+            for ((owner, index), deposit) in deposits.borrow().iter() {
+                if deposit.status == DepositStatus::Reverted {
+                    continue;
+                }
+                if let Some(transfer) = CreditedTransfer::of(&deposit) {
+                    transfers.insert(transfer, (owner, index));
+                }
+            }
+        })
+    });
+}
+
+/// Append a deposit to the deposits of the owner, unless the same token transfer is already
+/// credited, track it until its block is final and notify the subscribers.
+fn record_deposit(owner: Principal, deposit: Deposit) {
+    let transfer = CreditedTransfer::of(&deposit);
+    if let Some(transfer) = &transfer {
+        if CREDITED_TRANSFERS.with(|transfers| transfers.borrow().contains_key(transfer)) {
+            return;
+        }
+    }
+    let index = DEPOSIT_COUNTS
+        .with(|counts| counts.borrow().get(&owner))
+        .unwrap_or_else(|| deposit_count(owner));
+    ic_cdk::println!("Credited deposit to {}: {:?}", owner, deposit);
+    DEPOSITS.with(|deposits| deposits.borrow_mut().insert((owner, index), deposit.clone()));
+    DEPOSIT_COUNTS.with(|counts| counts.borrow_mut().insert(owner, index + 1));
+    if let Some(transfer) = transfer {
+        CREDITED_TRANSFERS.with(|transfers| transfers.borrow_mut().insert(transfer, (owner, index)));
+    }
+    mutate_state(|s| s.add_pending_deposit(owner, index));
+    notifications::notify_deposit(owner, index, &deposit);
+}
+
+// Number of deposits credited before the counts were stored.
+fn deposit_count(owner: Principal) -> u64 {
+    DEPOSITS.with(|deposits| {
+        deposits
+            .borrow()
+            .range((owner, 0)..=(owner, u64::MAX))
+            .last()
+            .map(|((_, index), _)| index + 1)
+            .unwrap_or_default()
+    })
+}
+
+fn get_deposit(owner: Principal, index: u64) -> Option<Deposit> {
+    DEPOSITS.with(|deposits| deposits.borrow().get(&(owner, index)))
+}
+
+// Record that the block of the deposit is final or was reorged out, stop tracking it
+// and notify the subscribers of the new status.
+fn update_deposit_status(owner: Principal, index: u64, status: DepositStatus) {
+    let updated = DEPOSITS.with(|deposits| {
+        let mut deposits = deposits.borrow_mut();
+        let mut deposit = deposits.get(&(owner, index))?;
+        deposit.status = status;
+        deposits.insert((owner, index), deposit.clone());
+        Some(deposit)
+    });
+    mutate_state(|s| s.remove_pending_deposit(owner, index));
+    if let Some(deposit) = updated {
+        if deposit.status == DepositStatus::Reverted {
+            // Credit the transfer again if it is included in another block.
+            if let Some(transfer) = CreditedTransfer::of(&deposit) {
+                CREDITED_TRANSFERS.with(|transfers| {
+                    let mut transfers = transfers.borrow_mut();
+                    if transfers.get(&transfer) == Some((owner, index)) {
+                        transfers.remove(&transfer);
+                    }
+                });
+            }
+        }
+        notifications::notify_deposit(owner, index, &deposit);
+    }
+}
+
+/// Return the deposits of the owner, oldest first.
+/// When a chain id is given, only the deposits on that chain are considered.
+pub fn get_deposits(
+    owner: Principal,
+    offset: u64,
+    limit: u64,
+    chain_id: Option<u64>,
+) -> Vec<Deposit> {
+    let limit = limit.min(MAX_DEPOSITS_PAGE_SIZE) as usize;
+    DEPOSITS.with(|deposits| {
+        let deposits = deposits.borrow();
+        match chain_id {
+            None => deposits
+                .range((owner, offset)..=(owner, u64::MAX))
+                .take(limit)
+                .map(|(_, deposit)| deposit)
+                .collect(),
+            Some(chain_id) => deposits
+                .range((owner, 0)..=(owner, u64::MAX))
+                .map(|(_, deposit)| deposit)
+                .filter(|deposit| deposit.chain_id == chain_id)
+                .skip(offset as usize)
+                .take(limit)
+                .collect(),
+        }
+    })
+}
+
+/// An ERC-20 `Transfer` event to a registered address.
+#[derive(Debug, PartialEq, Eq, Clone)]
+struct TokenTransfer {
+    token: String,
+    to: String,
+    amount: Nat,
+    block_number: u64,
+    block_hash: String,
+    tx_hash: String,
+    log_index: u64,
+}
+
+/// The ERC-20 `Transfer` events of an `eth_getLogs` response, and the logs that could not be parsed.
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+struct TransferLogs {
+    transfers: Vec<TokenTransfer>,
+    malformed: Vec<String>,
+}
+
+// -----------------------------------------------------------------------------
+// Scan the registered addresses of each chain for deposits up to the latest block
+// with the configured number of confirmations.
+// ERC-20 deposits are found from the `Transfer` logs to the registered addresses.
+// ETH deposits are found from increases of the balance between two scans, so an
+// incoming transfer is missed if the wallet spends as much in the same interval.
+// Only deposits made after the first scan of a chain are credited.
+// Credited deposits are checked again once their block is final: if the block
+// hash changed in the meantime, the deposit is reverted and the blocks from the
+// one of the deposit on are scanned again.
+// -----------------------------------------------------------------------------
+pub async fn scan_deposits() {
+    let _guard = match TimerGuard::new(TimerTask::ScanDeposits) {
+        Some(guard) => guard,
+        None => return,
+    };
+    // Deposits stay pending after their address is no longer registered, e.g. after a key change.
+    let mut pending_deposits: BTreeMap<u64, Vec<(Principal, u64, Deposit)>> = BTreeMap::new();
+    for (owner, index) in read_state(|s| s.pending_deposits()) {
+        if let Some(deposit) = get_deposit(owner, index) {
+            pending_deposits
+                .entry(deposit.chain_id)
+                .or_default()
+                .push((owner, index, deposit));
+        }
+    }
+    for (chain_id, deposits) in pending_deposits {
+        if let Err(e) = check_reorged_deposits(chain_id, deposits).await {
+            ic_cdk::println!("Failed to check deposits of chain {} for reorgs: {:?}", chain_id, e);
+        }
+    }
+    for chain_id in read_state(|s| s.deposit_chains()) {
+        if let Err(e) = scan_chain(chain_id).await {
+            ic_cdk::println!("Failed to scan chain {} for deposits: {:?}", chain_id, e);
+        }
+    }
+}
+
+// Finalize or revert the deposits of the chain whose block is now final, depending on
+// whether their block is still part of the canonical chain.
+async fn check_reorged_deposits(
+    chain_id: u64,
+    deposits: Vec<(Principal, u64, Deposit)>,
+) -> Result<(), WalletError> {
+    let chain = match read_state(|s| s.chain(Some(chain_id))) {
+        Ok(chain) => chain,
+        Err(WalletError::UnsupportedChain(_)) => {
+            // The chain was removed on upgrade: its deposits can no longer be checked.
+            ic_cdk::println!(
+                "Dropping {} pending deposits of removed chain {}",
+                deposits.len(),
+                chain_id
+            );
+            mutate_state(|s| {
+                for (owner, index, _) in &deposits {
+                    s.remove_pending_deposit(*owner, *index);
+                }
+            });
+            return Ok(());
+        }
+        Err(e) => return Err(e),
+    };
+    let finality_tag = read_state(|s| s.finality_tag());
+    let finalized_block = nat_to_u64(get_block(&chain, finality_tag.block_tag()).await?.0)?;
+    // Canonical hash of each block with deposits, fetched once per run.
+    let mut canonical_hashes: BTreeMap<u64, String> = BTreeMap::new();
+    for (owner, index, deposit) in deposits {
+        if deposit.block_number > finalized_block {
+            continue;
+        }
+        let canonical_hash = match canonical_hashes.get(&deposit.block_number) {
+            Some(hash) => hash.clone(),
+            None => {
+                let block = BlockTag::Number(Nat::from(deposit.block_number));
+                let (_, hash) = get_block(&chain, block).await?;
+                canonical_hashes.insert(deposit.block_number, hash.clone());
+                hash
+            }
+        };
+        if canonical_hash.eq_ignore_ascii_case(&deposit.block_hash) {
+            update_deposit_status(owner, index, DepositStatus::Finalized);
+            continue;
+        }
+        ic_cdk::println!(
+            "Deposit {:?} of {} was reorged out: block {} now has hash {}",
+            deposit,
+            owner,
+            deposit.block_number,
+            canonical_hash
+        );
+        update_deposit_status(owner, index, DepositStatus::Reverted);
+        mutate_state(|s| match &deposit.token {
+            // Detect the transfer again if it was included in another block.
+            Some(_) => s.rewind_deposit_scan(chain_id, deposit.block_number.saturating_sub(1)),
+            None => s.deduct_deposit_balance(chain_id, owner, &deposit.amount),
+        });
+    }
+    Ok(())
+}
+
+async fn scan_chain(chain_id: u64) -> Result<(), WalletError> {
+    let chain = read_state(|s| s.chain(Some(chain_id)))?;
+    let confirmations = read_state(|s| s.deposit_confirmations());
+    let latest_block = rpc::latest_block_number(&chain).await?;
+    let confirmed_block = match latest_block.checked_sub(confirmations) {
+        Some(block_number) => block_number,
+        None => return Ok(()),
+    };
+    let accounts = read_state(|s| s.deposit_accounts(chain_id));
+    let to_block = match read_state(|s| s.deposit_scanned_block(chain_id)) {
+        None => confirmed_block,
+        Some(scanned_block) if scanned_block >= confirmed_block => return Ok(()),
+        Some(scanned_block) => {
+            let from_block = scanned_block + 1;
+            let mut to_block = confirmed_block.min(scanned_block + MAX_BLOCKS_PER_SCAN);
+            let logs = loop {
+                match get_token_transfers(&chain, &accounts, from_block, to_block).await {
+                    Err(e) if to_block > from_block && is_too_many_logs(&e) => {
+                        to_block = from_block + (to_block - from_block) / 2;
+                    }
+                    result => break result?,
+                }
+            };
+            for malformed in logs.malformed {
+                ic_cdk::println!("Skipping malformed log on chain {}: {}", chain_id, malformed);
+            }
+            let owners: BTreeMap<&str, Principal> = accounts
+                .iter()
+                .map(|account| (account.address.as_str(), account.owner))
+                .collect();
+            for transfer in logs.transfers {
+                if let Some(owner) = owners.get(transfer.to.as_str()) {
+                    record_deposit(
+                        *owner,
+                        Deposit {
+                            chain_id,
+                            token: Some(transfer.token),
+                            amount: transfer.amount,
+                            block_number: transfer.block_number,
+                            block_hash: transfer.block_hash,
+                            tx_hash: Some(transfer.tx_hash),
+                            log_index: Some(transfer.log_index),
+                            timestamp: ic_cdk::api::time(),
+                            status: DepositStatus::Confirmed,
+                        },
+                    );
+                }
+            }
+            to_block
+        }
+    };
+    // Set in the same message as the deposits are recorded, so that they are never credited twice.
+    mutate_state(|s| s.set_deposit_scanned_block(chain_id, to_block));
+
+    if accounts.is_empty() {
+        return Ok(());
+    }
+    let (_, block_hash) = get_block(&chain, BlockTag::Number(Nat::from(to_block))).await?;
+    for account in accounts {
+        let address = parse_address(&account.address)?;
+        let block = BlockTag::Number(Nat::from(to_block));
+        let balance = match get_balance_at(&chain, &address, block).await {
+            Ok(balance) => balance,
+            Err(e) => {
+                ic_cdk::println!(
+                    "Failed to get the balance of {} at block {}: {:?}",
+                    account.address,
+                    to_block,
+                    e
+                );
+                continue;
+            }
+        };
+        match account.eth_balance {
+            Some(previous_balance) if balance > previous_balance => {
+                record_deposit(
+                    account.owner,
+                    Deposit {
+                        chain_id,
+                        token: None,
+                        amount: balance.clone() - previous_balance,
+                        block_number: to_block,
+                        block_hash: block_hash.clone(),
+                        tx_hash: None,
+                        log_index: None,
+                        timestamp: ic_cdk::api::time(),
+                        status: DepositStatus::Confirmed,
+                    },
+                );
+            }
+            _ => {}
+        }
+        mutate_state(|s| s.set_deposit_balance(chain_id, account.owner, balance));
+    }
+    Ok(())
+}
+
+// Fetch the ERC-20 `Transfer` logs to the given accounts between the given blocks (inclusive)
+// with the consensus of the configured providers.
+async fn get_token_transfers(
+    chain: &ChainConfig,
+    accounts: &[DepositAccount],
+    from_block: u64,
+    to_block: u64,
+) -> Result<TransferLogs, WalletError> {
+    let recipients = accounts
+        .iter()
+        .map(|account| {
+            parse_address(&account.address).map(|address| encode_address_topic(&address))
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+    let params = serde_json::json!([{
+        "fromBlock": rpc::block_tag_param(&BlockTag::Number(Nat::from(from_block))),
+        "toBlock": rpc::block_tag_param(&BlockTag::Number(Nat::from(to_block))),
+        "topics": [TRANSFER_EVENT_TOPIC, null, recipients],
+    }]);
+    rpc::multi_json_rpc_request_with(
+        chain,
+        "eth_getLogs",
+        params,
+        MAX_LOGS_RESPONSE_SIZE_BYTES,
+        parse_transfer_logs,
+    )
+    .await
+}
+
+fn is_too_many_logs(error: &WalletError) -> bool {
+    let message = format!("{:?}", error).to_lowercase();
+    TOO_MANY_LOGS_ERRORS
+        .iter()
+        .any(|too_many| message.contains(too_many))
+}
+
+// Parse the ERC-20 `Transfer` logs of an `eth_getLogs` response.
+// A malformed log is reported rather than failing the whole response,
+// so that it does not prevent the other transfers from being credited.
+fn parse_transfer_logs(logs: serde_json::Value) -> Result<TransferLogs, WalletError> {
+    let logs = logs
+        .as_array()
+        .ok_or_else(|| WalletError::RpcError(format!("expected an array of logs, got {}", logs)))?;
+    let mut result = TransferLogs::default();
+    for log in logs {
+        match parse_transfer_log(log) {
+            Ok(Some(transfer)) => result.transfers.push(transfer),
+            Ok(None) => {}
+            Err(reason) => result
+                .malformed
+                .push(format!("invalid log {}: {}", log, reason)),
+        }
+    }
+    Ok(result)
+}
+
+// Parse an ERC-20 `Transfer` log, or return `None` for removed logs and other events.
+fn parse_transfer_log(log: &serde_json::Value) -> Result<Option<TokenTransfer>, String> {
+    if log.get("removed").and_then(|removed| removed.as_bool()) == Some(true) {
+        return Ok(None);
+    }
+    let topics = log
+        .get("topics")
+        .and_then(|topics| topics.as_array())
+        .ok_or("missing topics")?
+        .iter()
+        .map(|topic| topic.as_str().ok_or("invalid topic"))
+        .collect::<Result<Vec<_>, _>>()?;
+    // ERC-721 transfers share the event signature but index the token id as a fourth topic.
+    if topics.len() != 3 || !topics[0].eq_ignore_ascii_case(TRANSFER_EVENT_TOPIC) {
+        return Ok(None);
+    }
+    let field = |name: &str| log.get(name).ok_or_else(|| format!("missing {}", name));
+    let string_field = |name: &str| {
+        field(name)?
+            .as_str()
+            .ok_or_else(|| format!("invalid {}", name))
+    };
+    let quantity_field = |name: &str| {
+        parse_hex_quantity(field(name)?)
+            .and_then(nat_to_u64)
+            .map_err(|e| format!("invalid {}: {:?}", name, e))
+    };
+    let data = hex::decode(string_field("data")?).map_err(|e| e.to_string())?;
+    Ok(Some(TokenTransfer {
+        token: decode_address(string_field("address")?)?,
+        to: decode_address_topic(topics[2])?,
+        amount: decode_uint256(&data)?,
+        block_number: quantity_field("blockNumber")?,
+        block_hash: string_field("blockHash")?.to_string(),
+        tx_hash: string_field("transactionHash")?.to_string(),
+        log_index: quantity_field("logIndex")?,
+    }))
+}
+
+/// Decode an address indexed as a 32-byte topic into its canonical form.
+fn decode_address_topic(topic: &str) -> Result<String, String> {
+    let word: [u8; 32] = hex::decode(topic)
+        .map_err(|e| e.to_string())?
+        .try_into()
+        .map_err(|_| format!("{} is not a 32-byte topic", topic))?;
+    let (padding, address) = word.split_at(12);
+    if padding.iter().any(|byte| *byte != 0) {
+        return Err(format!("{} is not an address topic", topic));
+    }
+    decode_address(&hex::encode(address))
+}
+
+/// Decode a hex-encoded 20-byte address, with or without checksum, into its canonical form.
+fn decode_address(hex_address: &str) -> Result<String, String> {
+    let bytes: [u8; 20] = hex::decode(hex_address)
+        .map_err(|e| e.to_string())?
+        .try_into()
+        .map_err(|_| format!("{} is not a 20-byte address", hex_address))?;
+    Ok(Address::new(bytes).to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOKEN: &str = "0xa0b86991c6218b36c1d19d4a2e9eb0ce3606eb48";
+    const RECIPIENT_TOPIC: &str =
+        "0x0000000000000000000000001111111111111111111111111111111111111111";
+    const SENDER_TOPIC: &str = "0x0000000000000000000000002222222222222222222222222222222222222222";
+
+    fn transfer_log(recipient_topic: &str, log_index: &str) -> serde_json::Value {
+        serde_json::json!({
+            "address": TOKEN,
+            "topics": [TRANSFER_EVENT_TOPIC, SENDER_TOPIC, recipient_topic],
+            "data": "0x00000000000000000000000000000000000000000000000000000000000f4240",
+            "blockNumber": "0x64",
+            "blockHash": "0xabc",
+            "transactionHash": "0xdef",
+            "logIndex": log_index,
+            "removed": false,
+        })
+    }
+
+    fn expected_transfer(log_index: u64) -> TokenTransfer {
+        TokenTransfer {
+            token: decode_address(TOKEN).unwrap(),
+            to: decode_address("0x1111111111111111111111111111111111111111").unwrap(),
+            amount: Nat::from(1_000_000_u64),
+            block_number: 100,
+            block_hash: "0xabc".to_string(),
+            tx_hash: "0xdef".to_string(),
+            log_index,
+        }
+    }
+
+    #[test]
+    fn should_parse_transfer_logs() {
+        let logs = serde_json::json!([
+            transfer_log(RECIPIENT_TOPIC, "0x0"),
+            transfer_log(RECIPIENT_TOPIC, "0x1")
+        ]);
+        assert_eq!(
+            parse_transfer_logs(logs),
+            Ok(TransferLogs {
+                transfers: vec![expected_transfer(0), expected_transfer(1)],
+                malformed: vec![],
+            })
+        );
+    }
+
+    #[test]
+    fn should_skip_malformed_logs() {
+        let mut missing_data = transfer_log(RECIPIENT_TOPIC, "0x1");
+        missing_data.as_object_mut().unwrap().remove("data");
+        let logs = serde_json::json!([
+            transfer_log(RECIPIENT_TOPIC, "0x0"),
+            missing_data,
+            transfer_log("0xé", "0x2"),
+            transfer_log(RECIPIENT_TOPIC, "not a quantity"),
+        ]);
+        let result = parse_transfer_logs(logs).unwrap();
+        assert_eq!(result.transfers, vec![expected_transfer(0)]);
+        assert_eq!(result.malformed.len(), 3);
+    }
+
+    #[test]
+    fn should_ignore_removed_logs_and_other_events() {
+        let mut removed = transfer_log(RECIPIENT_TOPIC, "0x0");
+        removed["removed"] = serde_json::Value::Bool(true);
+        let mut erc721 = transfer_log(RECIPIENT_TOPIC, "0x1");
+        erc721["topics"]
+            .as_array_mut()
+            .unwrap()
+            .push(serde_json::json!(SENDER_TOPIC));
+        let logs = serde_json::json!([removed, erc721]);
+        assert_eq!(parse_transfer_logs(logs), Ok(TransferLogs::default()));
+    }
+
+    #[test]
+    fn should_reject_invalid_address_topics() {
+        assert_eq!(
+            decode_address_topic(RECIPIENT_TOPIC),
+            decode_address("0x1111111111111111111111111111111111111111")
+        );
+        // Non-zero padding.
+        assert!(decode_address_topic(
+            "0x0000000000000000000000011111111111111111111111111111111111111111"
+        )
+        .is_err());
+        // Too short, and non-ASCII.
+        assert!(decode_address_topic("0x1111111111111111111111111111111111111111").is_err());
+        assert!(decode_address_topic("0x000000000000000000000000ééééééééééééééééééééé1").is_err());
+    }
+
+    #[test]
+    fn should_fail_on_non_array_response() {
+        assert!(parse_transfer_logs(serde_json::json!({"logs": []})).is_err());
+    }
+}
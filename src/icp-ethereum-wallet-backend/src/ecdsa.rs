@@ -44,6 +44,17 @@ impl From<EcdsaPublicKeyResponse> for EcdsaPublicKey {
     }
 }
 
+impl From<&EcdsaPublicKey> for EcdsaPublicKeyResponse {
+    /// Converts an `EcdsaPublicKey` back into the response format of the IC management canister,
+    /// so that it can be serialized (e.g. to stable memory).
+    fn from(value: &EcdsaPublicKey) -> Self {
+        EcdsaPublicKeyResponse {
+            public_key: value.public_key.serialize_sec1(true),
+            chain_code: value.chain_code.clone(),
+        }
+    }
+}
+
 impl From<&EcdsaPublicKey> for Address {
     /// Converts an `EcdsaPublicKey` into an Ethereum `Address`.
     /// Uses the standard Ethereum rule: Keccak256(uncompressed_pubkey[1..]) → last 20 bytes.
@@ -0,0 +1,72 @@
+use alloy_primitives::{hex, U256};
+use candid::Nat;
+use ic_ethereum_types::Address;
+use num::BigUint;
+
+/// Function selector of `transfer(address,uint256)`, i.e. the first 4 bytes of its Keccak256 hash.
+const TRANSFER_SELECTOR: [u8; 4] = [0xa9, 0x05, 0x9c, 0xbb];
+
+/// Function selector of `balanceOf(address)`.
+const BALANCE_OF_SELECTOR: [u8; 4] = [0x70, 0xa0, 0x82, 0x31];
+
+/// Function selector of `allowance(address,address)`.
+const ALLOWANCE_SELECTOR: [u8; 4] = [0xdd, 0x62, 0xed, 0x3e];
+
+/// Topic of the `Transfer(address,address,uint256)` event, i.e. the Keccak256 hash of its signature.
+pub const TRANSFER_EVENT_TOPIC: &str =
+    "0xddf252ad1be2c89b69c2b068fc378daa952ba7f163c4a11628f55a4df523b3ef";
+
+// -----------------------------------------------------------------------------
+// ABI-encode a call to `transfer(address,uint256)`
+// The calldata is the function selector followed by each argument padded to 32 bytes.
+// -----------------------------------------------------------------------------
+pub fn encode_transfer(to: &Address, amount: U256) -> Vec<u8> {
+    let mut calldata = TRANSFER_SELECTOR.to_vec();
+    calldata.extend_from_slice(&encode_address(to));
+    calldata.extend_from_slice(&amount.to_be_bytes::<32>());
+    calldata
+}
+
+// -----------------------------------------------------------------------------
+// ABI-encode a call to `balanceOf(address)`
+// -----------------------------------------------------------------------------
+pub fn encode_balance_of(owner: &Address) -> Vec<u8> {
+    let mut calldata = BALANCE_OF_SELECTOR.to_vec();
+    calldata.extend_from_slice(&encode_address(owner));
+    calldata
+}
+
+// -----------------------------------------------------------------------------
+// ABI-encode a call to `allowance(address,address)`
+// -----------------------------------------------------------------------------
+pub fn encode_allowance(owner: &Address, spender: &Address) -> Vec<u8> {
+    let mut calldata = ALLOWANCE_SELECTOR.to_vec();
+    calldata.extend_from_slice(&encode_address(owner));
+    calldata.extend_from_slice(&encode_address(spender));
+    calldata
+}
+
+// -----------------------------------------------------------------------------
+// Decode the 32-byte `uint256` returned by `balanceOf` and `allowance`
+// -----------------------------------------------------------------------------
+pub fn decode_uint256(return_data: &[u8]) -> Result<Nat, String> {
+    if return_data.len() != 32 {
+        return Err(format!(
+            "expected 32 bytes of return data but got {} bytes",
+            return_data.len()
+        ));
+    }
+    Ok(Nat(BigUint::from_bytes_be(return_data)))
+}
+
+/// Encode an address as an indexed event topic, e.g. to filter `Transfer` logs by recipient.
+pub fn encode_address_topic(address: &Address) -> String {
+    format!("0x{}", hex::encode(encode_address(address)))
+}
+
+/// Left-pad a 20-byte address to a 32-byte ABI word.
+fn encode_address(address: &Address) -> [u8; 32] {
+    let mut word = [0u8; 32];
+    word[12..].copy_from_slice(&address.into_bytes());
+    word
+}
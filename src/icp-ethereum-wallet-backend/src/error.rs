@@ -0,0 +1,46 @@
+use crate::receipts::TransactionStatus;
+use candid::{CandidType, Deserialize, Nat};
+
+/// Errors returned by the canister's public methods.
+#[derive(CandidType, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub enum WalletError {
+    /// The anonymous principal cannot own a wallet.
+    AnonymousCaller,
+    /// The recipient is not a valid Ethereum address.
+    InvalidRecipient { address: String, reason: String },
+    /// Another address (token, spender, contract) is not a valid Ethereum address.
+    InvalidAddress { address: String, reason: String },
+    /// An argument is malformed or out of range.
+    InvalidArgument(String),
+    /// The wallet cannot pay for the value and fees of the transaction.
+    InsufficientFunds,
+    /// The nonce was already used by another transaction of the wallet.
+    NonceTooLow,
+    /// The nonce leaves a gap after the last transaction of the wallet.
+    NonceTooHigh,
+    /// The estimated max fee per gas exceeds the configured ceiling.
+    FeeTooHigh { max_fee_per_gas: Nat, ceiling: Nat },
+    /// The RPC providers returned different results.
+    RpcInconsistent(String),
+    /// The call to the EVM RPC canister or to a provider failed.
+    RpcError(String),
+    /// Another send from the same principal is in progress.
+    Busy,
+    /// The ECDSA public key could not be fetched from the management canister.
+    EcdsaKeyUnavailable(String),
+    /// The threshold ECDSA signature could not be obtained.
+    SigningFailed(String),
+    /// The canister did not send a transaction with this hash for the caller.
+    TransactionNotFound(String),
+    /// The transaction is no longer pending and cannot be replaced.
+    TransactionNotPending {
+        tx_hash: String,
+        status: TransactionStatus,
+    },
+    /// The canister is not configured to operate on the chain with this id.
+    UnsupportedChain(u64),
+    /// The caller is not allowed to perform the operation.
+    Unauthorized(String),
+    /// No more addresses can be scanned for deposits on the chain.
+    TooManyDepositAddresses { chain_id: u64, max: u64 },
+}
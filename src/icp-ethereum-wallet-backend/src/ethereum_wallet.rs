@@ -1,4 +1,5 @@
 use crate::ecdsa::EcdsaPublicKey; // Our wrapper around ECDSA public keys
+use crate::error::WalletError; // Errors returned to callers
 use crate::state::{lazy_call_ecdsa_public_key, read_state}; // Utilities for accessing state and fetching public keys
 use candid::Principal; // Identity type on the Internet Computer
 use ic_secp256k1::{PublicKey, RecoveryId}; // Secp256k1 crypto primitives
@@ -26,12 +27,19 @@ impl EthereumWallet {
     // Create a new wallet for a given owner Principal
     // Fetches the canister's ECDSA public key (lazy), derives a per-owner key
     // -------------------------------------------------------------------------
-    pub async fn new(owner: Principal) -> Self {
-        let derived_public_key = derive_public_key(&owner, &lazy_call_ecdsa_public_key().await);
-        Self {
+    pub async fn new(owner: Principal) -> Result<Self, WalletError> {
+        let derived_public_key = derive_public_key(&owner, &lazy_call_ecdsa_public_key().await?);
+        Ok(Self {
             owner,
             derived_public_key,
-        }
+        })
+    }
+
+    // -------------------------------------------------------------------------
+    // Return the Principal owning this wallet
+    // -------------------------------------------------------------------------
+    pub fn owner(&self) -> Principal {
+        self.owner
     }
 
     // -------------------------------------------------------------------------
@@ -45,7 +53,10 @@ impl EthereumWallet {
     // Sign a 32-byte message hash with ECDSA using the management canister
     // Returns the 64-byte signature and the RecoveryId
     // -------------------------------------------------------------------------
-    pub async fn sign_with_ecdsa(&self, message_hash: [u8; 32]) -> ([u8; 64], RecoveryId) {
+    pub async fn sign_with_ecdsa(
+        &self,
+        message_hash: [u8; 32],
+    ) -> Result<([u8; 64], RecoveryId), WalletError> {
         use ic_cdk::api::management_canister::ecdsa::SignWithEcdsaArgument;
 
         // Derive path for this wallet (unique to owner)
@@ -61,7 +72,12 @@ impl EthereumWallet {
                 key_id,
             })
             .await
-            .expect("failed to sign with ecdsa");
+            .map_err(|(error_code, message)| {
+                WalletError::SigningFailed(format!(
+                    "failed to sign with ecdsa: {:?}: {}",
+                    error_code, message
+                ))
+            })?;
 
         // Ensure the signature is exactly 64 bytes (r,s components of secp256k1)
         let signature_length = result.signature.len();
@@ -79,7 +95,7 @@ impl EthereumWallet {
         if recovery_id.is_x_reduced() {
             ic_cdk::trap("BUG: affine x-coordinate of r is reduced which is so unlikely to happen that it's probably a bug");
         }
-        (signature, recovery_id)
+        Ok((signature, recovery_id))
     }
 
     // -------------------------------------------------------------------------
@@ -0,0 +1,89 @@
+use crate::error::WalletError;
+use crate::guard::{TimerGuard, TimerTask};
+use crate::rpc;
+use crate::state::{mutate_state, read_state};
+use crate::{replace_sent_transaction, Replacement};
+use std::collections::BTreeMap;
+use std::time::Duration;
+
+/// How often pending transactions are checked for a fee bump.
+pub const FEE_BUMP_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Fee bumps never raise the max fee per gas of a transaction above this multiple
+/// of the max fee per gas it was first sent with.
+pub const MAX_FEE_BUMP_FACTOR: u128 = 2;
+
+// -----------------------------------------------------------------------------
+// Replace the transactions that have been pending for more than the configured
+// number of blocks with the same transaction paying higher fees.
+// The canister does not know in which block a transaction was sent, so the
+// count starts at the latest block when the transaction is first seen pending.
+// Each transaction is bumped at most `max_fee_bumps` times, and never above
+// `MAX_FEE_BUMP_FACTOR` times its initial max fee per gas nor the max fee per
+// gas ceiling. Replacements requested by the owner do not count.
+// -----------------------------------------------------------------------------
+pub async fn bump_stuck_transactions() {
+    let after_blocks = match read_state(|s| s.fee_bump_after_blocks()) {
+        Some(after_blocks) => after_blocks,
+        None => return,
+    };
+    let _guard = match TimerGuard::new(TimerTask::BumpFees) {
+        Some(guard) => guard,
+        None => return,
+    };
+    let transactions = read_state(|s| s.replaceable_transactions());
+    if transactions.is_empty() {
+        return;
+    }
+    let max_fee_bumps = read_state(|s| s.max_fee_bumps());
+    // Latest block of each chain, fetched once per run.
+    let mut latest_blocks: BTreeMap<u64, u64> = BTreeMap::new();
+    for (tx_hash, transaction) in transactions {
+        let latest_block = match latest_blocks.get(&transaction.chain_id) {
+            Some(block_number) => *block_number,
+            None => match latest_block_number(transaction.chain_id).await {
+                Ok(block_number) => {
+                    latest_blocks.insert(transaction.chain_id, block_number);
+                    block_number
+                }
+                Err(e) => {
+                    ic_cdk::println!(
+                        "Failed to get the latest block number of chain {}: {:?}",
+                        transaction.chain_id,
+                        e
+                    );
+                    continue;
+                }
+            },
+        };
+        let pending_since_block = match transaction.pending_since_block {
+            Some(block_number) => block_number,
+            None => {
+                mutate_state(|s| s.set_pending_since_block(&tx_hash, latest_block));
+                continue;
+            }
+        };
+        if latest_block.saturating_sub(pending_since_block) < after_blocks
+            || transaction.fee_bumps >= max_fee_bumps
+        {
+            continue;
+        }
+        match replace_sent_transaction(transaction.owner, &tx_hash, Replacement::FeeBump).await {
+            Ok(new_tx_hash) => ic_cdk::println!(
+                "Bumped fees of transaction {} pending since block {} (latest block {}): replaced by {} (bump {} of {})",
+                tx_hash,
+                pending_since_block,
+                latest_block,
+                new_tx_hash,
+                transaction.fee_bumps + 1,
+                max_fee_bumps
+            ),
+            Err(e) => ic_cdk::println!("Failed to bump fees of transaction {}: {:?}", tx_hash, e),
+        }
+    }
+}
+
+async fn latest_block_number(chain_id: u64) -> Result<u64, WalletError> {
+    let chain = read_state(|s| s.chain(Some(chain_id)))?;
+    rpc::latest_block_number(&chain).await
+}
@@ -0,0 +1,335 @@
+use crate::error::WalletError;
+use crate::rpc::{json_rpc_request, parse_hex_quantity};
+use crate::state::read_state;
+use crate::{nat_to_u128, ChainConfig, EthereumNetwork, EVM_RPC};
+use alloy_primitives::{hex, U256};
+use candid::Nat;
+use evm_rpc_canister_types::{
+    BlockTag, FeeHistory, FeeHistoryArgs, FeeHistoryResult, MultiFeeHistoryResult,
+};
+use ic_ethereum_types::Address;
+use serde_bytes::ByteBuf;
+
+/// Standard gas limit for an Ethereum transfer to an EOA.
+pub const ETH_TRANSFER_GAS_LIMIT: u128 = 21_000;
+
+/// Maximum size of an `eth_getCode` response: contracts are limited to 24 KiB of bytecode (EIP-170),
+/// i.e. about 48 KiB once hex encoded.
+const GET_CODE_MAX_RESPONSE_SIZE_BYTES: u64 = 52_000;
+
+/// Number of recent blocks to look at when estimating fees.
+const FEE_HISTORY_BLOCK_COUNT: u8 = 5;
+
+/// Percentile of the priority fees paid in each block that we aim for.
+const PRIORITY_FEE_PERCENTILE: u8 = 20;
+
+/// Lower bound on the priority fee on Ethereum, so that a quiet period does not lead to a tip of zero.
+const L1_MIN_MAX_PRIORITY_FEE_PER_GAS: u128 = 1_500_000_000;
+
+/// Minimum increase, in percent, of both fees for nodes to accept a replacement transaction.
+const REPLACEMENT_FEE_BUMP_PERCENT: u128 = 10;
+
+/// Fees to be used in an EIP-1559 transaction.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FeeEstimates {
+    pub max_fee_per_gas: u128,
+    pub max_priority_fee_per_gas: u128,
+}
+
+// -----------------------------------------------------------------------------
+// Estimate the EIP-1559 fees from the fee history of the last few blocks.
+// The max fee per gas leaves room for the base fee to double before the
+// transaction is included, and is capped by the ceiling configured in state.
+// -----------------------------------------------------------------------------
+pub async fn estimate_transaction_fees(chain: &ChainConfig) -> Result<FeeEstimates, WalletError> {
+    let estimates = estimate_fees_from_history(chain).await?;
+    let ceiling = read_state(|s| s.max_fee_per_gas_ceiling());
+    if estimates.max_fee_per_gas > ceiling {
+        return Err(WalletError::FeeTooHigh {
+            max_fee_per_gas: Nat::from(estimates.max_fee_per_gas),
+            ceiling: Nat::from(ceiling),
+        });
+    }
+    Ok(estimates)
+}
+
+// -----------------------------------------------------------------------------
+// Compute the fees of a transaction replacing a pending one with the same nonce.
+// Nodes only accept the replacement if both fees increase by at least 10%.
+// The fees are raised further if the current estimates are higher, and are
+// capped by the given cap of the transaction and the ceiling configured in state.
+// -----------------------------------------------------------------------------
+pub async fn estimate_replacement_fees(
+    chain: &ChainConfig,
+    previous: FeeEstimates,
+    max_fee_per_gas_cap: u128,
+) -> Result<FeeEstimates, WalletError> {
+    let current = estimate_fees_from_history(chain).await?;
+    let bump = |fee: u128| {
+        fee.saturating_mul(100 + REPLACEMENT_FEE_BUMP_PERCENT)
+            .div_ceil(100)
+    };
+    let max_priority_fee_per_gas = bump(previous.max_priority_fee_per_gas)
+        .max(current.max_priority_fee_per_gas);
+    let max_fee_per_gas = bump(previous.max_fee_per_gas)
+        .max(current.max_fee_per_gas)
+        .max(max_priority_fee_per_gas);
+    let ceiling = read_state(|s| s.max_fee_per_gas_ceiling()).min(max_fee_per_gas_cap);
+    if max_fee_per_gas > ceiling {
+        return Err(WalletError::FeeTooHigh {
+            max_fee_per_gas: Nat::from(max_fee_per_gas),
+            ceiling: Nat::from(ceiling),
+        });
+    }
+    Ok(FeeEstimates {
+        max_fee_per_gas,
+        max_priority_fee_per_gas,
+    })
+}
+
+// Return the lower bound on the priority fee of the network.
+// Rollups order transactions by arrival at their sequencer and typically pay
+// tips far below a gwei, so their median tip is used as is.
+fn min_max_priority_fee_per_gas(ethereum_network: &EthereumNetwork) -> u128 {
+    match ethereum_network {
+        EthereumNetwork::Mainnet | EthereumNetwork::Sepolia => L1_MIN_MAX_PRIORITY_FEE_PER_GAS,
+        EthereumNetwork::ArbitrumOne
+        | EthereumNetwork::BaseMainnet
+        | EthereumNetwork::OptimismMainnet
+        | EthereumNetwork::Custom { .. } => 0,
+    }
+}
+
+fn estimates_from_fee_history(
+    fee_history: &FeeHistory,
+    min_max_priority_fee_per_gas: u128,
+) -> Result<FeeEstimates, WalletError> {
+    // The fee history contains one more base fee than blocks requested:
+    // the last one is the base fee of the next block.
+    let base_fee_per_gas_next_block = fee_history
+        .baseFeePerGas
+        .last()
+        .cloned()
+        .map(nat_to_u128)
+        .ok_or_else(|| {
+            WalletError::RpcError("fee history does not contain any base fee".to_string())
+        })??;
+
+    let mut priority_fees: Vec<u128> = fee_history
+        .reward
+        .iter()
+        .filter_map(|rewards| rewards.first().cloned())
+        .map(nat_to_u128)
+        .collect::<Result<_, _>>()?;
+    priority_fees.sort_unstable();
+    let median_priority_fee = priority_fees
+        .get(priority_fees.len() / 2)
+        .copied()
+        .unwrap_or_default();
+    let max_priority_fee_per_gas = median_priority_fee.max(min_max_priority_fee_per_gas);
+
+    Ok(FeeEstimates {
+        max_fee_per_gas: base_fee_per_gas_next_block
+            .saturating_mul(2)
+            .saturating_add(max_priority_fee_per_gas),
+        max_priority_fee_per_gas,
+    })
+}
+
+// Fetch the fee history with the consensus of the providers and derive fee estimates.
+// When the providers disagree, the estimates computed from their fee histories are
+// reconciled according to the consensus strategy of the chain.
+async fn estimate_fees_from_history(chain: &ChainConfig) -> Result<FeeEstimates, WalletError> {
+    let rpc_services = chain.evm_rpc_services();
+    let evm_rpc_cycles = read_state(|s| s.evm_rpc_cycles());
+    let min_priority_fee = min_max_priority_fee_per_gas(&chain.ethereum_network);
+    let args = FeeHistoryArgs {
+        blockCount: Nat::from(FEE_HISTORY_BLOCK_COUNT),
+        newestBlock: BlockTag::Latest,
+        rewardPercentiles: Some(ByteBuf::from(vec![PRIORITY_FEE_PERCENTILE])),
+    };
+    let (result,) = EVM_RPC
+        .eth_fee_history(rpc_services, None, args.clone(), evm_rpc_cycles)
+        .await
+        .map_err(|e| {
+            WalletError::RpcError(format!(
+                "failed to get fee history for {:?}, error: {:?}",
+                args, e
+            ))
+        })?;
+    match result {
+        MultiFeeHistoryResult::Consistent(consistent_result) => match consistent_result {
+            FeeHistoryResult::Ok(Some(fee_history)) => {
+                estimates_from_fee_history(&fee_history, min_priority_fee)
+            }
+            FeeHistoryResult::Ok(None) => Err(WalletError::RpcError(format!(
+                "empty fee history for {:?}",
+                args
+            ))),
+            FeeHistoryResult::Err(error) => Err(WalletError::RpcError(format!(
+                "failed to get fee history for {:?}, error: {:?}",
+                args, error
+            ))),
+        },
+        MultiFeeHistoryResult::Inconsistent(inconsistent_results) => chain
+            .consensus_strategy()
+            .reduce(
+                inconsistent_results
+                    .iter()
+                    .map(|(_, result)| match result {
+                        FeeHistoryResult::Ok(Some(fee_history)) => {
+                            estimates_from_fee_history(fee_history, min_priority_fee).ok()
+                        }
+                        _ => None,
+                    })
+                    .collect(),
+            )
+            .ok_or_else(|| {
+                WalletError::RpcInconsistent(format!(
+                    "inconsistent results when retrieving fee history for {:?}. Received results: {:?}",
+                    args, inconsistent_results
+                ))
+            }),
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Estimate the gas limit of a transaction.
+// Plain transfers to an EOA use the standard 21,000 gas. As soon as calldata is
+// present or the recipient is a contract, the limit is obtained from
+// `eth_estimateGas` and increased by the buffer configured in state.
+// -----------------------------------------------------------------------------
+pub async fn estimate_gas_limit(
+    chain: &ChainConfig,
+    from: &Address,
+    to: &Address,
+    value: U256,
+    input: &[u8],
+) -> Result<u128, WalletError> {
+    if input.is_empty() && !has_code(chain, to).await? {
+        return Ok(ETH_TRANSFER_GAS_LIMIT);
+    }
+    let params = serde_json::json!([{
+        "from": from.to_string(),
+        "to": to.to_string(),
+        "value": format!("{:#x}", value),
+        "data": format!("0x{}", hex::encode(input)),
+    }]);
+    let gas_estimate = nat_to_u128(parse_hex_quantity(
+        &json_rpc_request(chain, "eth_estimateGas", params, 500).await?,
+    )?)?;
+    let buffer_percent = read_state(|s| s.gas_limit_buffer_percent());
+    Ok(gas_estimate.saturating_mul(100 + buffer_percent as u128) / 100)
+}
+
+async fn has_code(chain: &ChainConfig, address: &Address) -> Result<bool, WalletError> {
+    let params = serde_json::json!([address.to_string(), "latest"]);
+    let code =
+        json_rpc_request(chain, "eth_getCode", params, GET_CODE_MAX_RESPONSE_SIZE_BYTES).await?;
+    Ok(!matches!(code.as_str(), Some("0x") | Some("")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GWEI: u128 = 1_000_000_000;
+
+    fn fee_history(base_fees: &[u128], rewards: &[u128]) -> FeeHistory {
+        FeeHistory {
+            oldestBlock: Nat::from(100_u64),
+            baseFeePerGas: base_fees.iter().map(|fee| Nat::from(*fee)).collect(),
+            gasUsedRatio: vec![0.5; rewards.len()],
+            reward: rewards
+                .iter()
+                .map(|reward| vec![Nat::from(*reward)])
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn should_double_next_base_fee_and_add_median_tip() {
+        let history = fee_history(
+            &[
+                10 * GWEI,
+                12 * GWEI,
+                11 * GWEI,
+                14 * GWEI,
+                13 * GWEI,
+                15 * GWEI,
+            ],
+            &[3 * GWEI, GWEI, 5 * GWEI, 2 * GWEI, 4 * GWEI],
+        );
+        assert_eq!(
+            estimates_from_fee_history(&history, 0),
+            Ok(FeeEstimates {
+                max_fee_per_gas: 2 * 15 * GWEI + 3 * GWEI,
+                max_priority_fee_per_gas: 3 * GWEI,
+            })
+        );
+    }
+
+    #[test]
+    fn should_apply_priority_fee_floor() {
+        let history = fee_history(&[10 * GWEI, 10 * GWEI], &[1_000_000]);
+        assert_eq!(
+            estimates_from_fee_history(&history, L1_MIN_MAX_PRIORITY_FEE_PER_GAS),
+            Ok(FeeEstimates {
+                max_fee_per_gas: 20 * GWEI + L1_MIN_MAX_PRIORITY_FEE_PER_GAS,
+                max_priority_fee_per_gas: L1_MIN_MAX_PRIORITY_FEE_PER_GAS,
+            })
+        );
+        assert_eq!(
+            estimates_from_fee_history(&history, 0),
+            Ok(FeeEstimates {
+                max_fee_per_gas: 20 * GWEI + 1_000_000,
+                max_priority_fee_per_gas: 1_000_000,
+            })
+        );
+    }
+
+    #[test]
+    fn should_use_floor_without_rewards() {
+        let history = fee_history(&[GWEI], &[]);
+        assert_eq!(
+            estimates_from_fee_history(&history, L1_MIN_MAX_PRIORITY_FEE_PER_GAS),
+            Ok(FeeEstimates {
+                max_fee_per_gas: 2 * GWEI + L1_MIN_MAX_PRIORITY_FEE_PER_GAS,
+                max_priority_fee_per_gas: L1_MIN_MAX_PRIORITY_FEE_PER_GAS,
+            })
+        );
+    }
+
+    #[test]
+    fn should_fail_without_base_fee() {
+        let history = fee_history(&[], &[GWEI]);
+        assert!(matches!(
+            estimates_from_fee_history(&history, 0),
+            Err(WalletError::RpcError(_))
+        ));
+    }
+
+    #[test]
+    fn should_only_apply_floor_on_ethereum() {
+        assert_eq!(
+            min_max_priority_fee_per_gas(&EthereumNetwork::Mainnet),
+            L1_MIN_MAX_PRIORITY_FEE_PER_GAS
+        );
+        assert_eq!(
+            min_max_priority_fee_per_gas(&EthereumNetwork::Sepolia),
+            L1_MIN_MAX_PRIORITY_FEE_PER_GAS
+        );
+        assert_eq!(
+            min_max_priority_fee_per_gas(&EthereumNetwork::ArbitrumOne),
+            0
+        );
+        assert_eq!(
+            min_max_priority_fee_per_gas(&EthereumNetwork::BaseMainnet),
+            0
+        );
+        assert_eq!(
+            min_max_priority_fee_per_gas(&EthereumNetwork::OptimismMainnet),
+            0
+        );
+    }
+}
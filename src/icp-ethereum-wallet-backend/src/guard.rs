@@ -0,0 +1,85 @@
+use crate::error::WalletError;
+use crate::state::mutate_state;
+use candid::Principal;
+
+/// Guard allowing a single send in flight per owner principal.
+///
+/// Update calls interleave at every `await`, so without it two sends from the same
+/// principal could both pass validation and both be signed. The lock is released when
+/// the guard is dropped, which also happens when the call traps after an `await`.
+#[must_use]
+#[derive(Debug, PartialEq, Eq)]
+pub struct SendGuard {
+    owner: Principal,
+}
+
+impl SendGuard {
+    pub fn new(owner: Principal) -> Result<Self, WalletError> {
+        mutate_state(|s| {
+            if s.try_lock_send(owner) {
+                Ok(Self { owner })
+            } else {
+                Err(WalletError::Busy)
+            }
+        })
+    }
+}
+
+impl Drop for SendGuard {
+    fn drop(&mut self) {
+        mutate_state(|s| s.unlock_send(&self.owner));
+    }
+}
+
+/// Guard limiting the number of notifications awaiting the reply of a subscriber,
+/// so that an unresponsive subscriber cannot accumulate outstanding calls.
+#[must_use]
+#[derive(Debug, PartialEq, Eq)]
+pub struct DeliveryGuard {
+    subscriber: Principal,
+}
+
+impl DeliveryGuard {
+    /// Returns `None` if `max` deliveries to the subscriber are already in flight.
+    pub fn new(subscriber: Principal, max: usize) -> Option<Self> {
+        mutate_state(|s| {
+            s.try_start_delivery(subscriber, max)
+                .then_some(Self { subscriber })
+        })
+    }
+}
+
+impl Drop for DeliveryGuard {
+    fn drop(&mut self) {
+        mutate_state(|s| s.finish_delivery(&self.subscriber));
+    }
+}
+
+/// Periodic tasks run by timers.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum TimerTask {
+    PollReceipts,
+    BumpFees,
+    ScanDeposits,
+}
+
+/// Guard preventing a periodic task from running again while a previous run is
+/// still awaiting responses.
+#[must_use]
+#[derive(Debug, PartialEq, Eq)]
+pub struct TimerGuard {
+    task: TimerTask,
+}
+
+impl TimerGuard {
+    /// Returns `None` if the task is already running.
+    pub fn new(task: TimerTask) -> Option<Self> {
+        mutate_state(|s| s.try_lock_task(task).then_some(Self { task }))
+    }
+}
+
+impl Drop for TimerGuard {
+    fn drop(&mut self) {
+        mutate_state(|s| s.unlock_task(self.task));
+    }
+}
@@ -0,0 +1,192 @@
+use crate::receipts::TransactionStatus;
+use crate::storage::{history_index_memory, history_lengths_memory, history_memory, VMem};
+use alloy_consensus::TxEip1559;
+use alloy_primitives::hex;
+use candid::{CandidType, Decode, Deserialize, Encode, Nat, Principal};
+use ic_ethereum_types::Address;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableBTreeMap, Storable};
+use std::borrow::Cow;
+use std::cell::RefCell;
+
+/// Maximum number of transactions returned by a single `get_transactions` call.
+pub const MAX_TRANSACTIONS_PAGE_SIZE: u64 = 100;
+
+/// A transaction signed by the canister on behalf of a principal.
+#[derive(CandidType, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct TransactionRecord {
+    pub chain_id: u64,
+    pub nonce: u64,
+    pub to: String,
+    pub value: Nat,
+    /// Keccak256 hash of the calldata, if any.
+    pub data_hash: Option<String>,
+    pub gas_limit: Nat,
+    pub max_fee_per_gas: Nat,
+    pub max_priority_fee_per_gas: Nat,
+    pub tx_hash: String,
+    /// The signed transaction as sent to the network, hex encoded.
+    pub raw_transaction: String,
+    /// Time at which the transaction was signed, in nanoseconds since the epoch.
+    pub timestamp: u64,
+    pub status: TransactionStatus,
+    /// Hash of the transaction with the same nonce that this one replaced.
+    pub replaces: Option<String>,
+    /// Hash of the transaction with the same nonce that replaced this one.
+    pub replaced_by: Option<String>,
+}
+
+impl TransactionRecord {
+    pub fn new(
+        transaction: &TxEip1559,
+        tx_hash: String,
+        raw_transaction: String,
+        timestamp: u64,
+    ) -> Self {
+        Self {
+            chain_id: transaction.chain_id,
+            nonce: transaction.nonce,
+            to: transaction
+                .to
+                .to()
+                .map(|to| Address::new(to.into_array()).to_string())
+                .unwrap_or_default(),
+            value: Nat::from(num::BigUint::from_bytes_be(
+                &transaction.value.to_be_bytes::<32>(),
+            )),
+            data_hash: (!transaction.input.is_empty()).then(|| {
+                format!(
+                    "0x{}",
+                    hex::encode(ic_sha3::Keccak256::hash(&transaction.input))
+                )
+            }),
+            gas_limit: Nat::from(transaction.gas_limit),
+            max_fee_per_gas: Nat::from(transaction.max_fee_per_gas),
+            max_priority_fee_per_gas: Nat::from(transaction.max_priority_fee_per_gas),
+            tx_hash,
+            raw_transaction,
+            timestamp,
+            status: TransactionStatus::Pending,
+            replaces: None,
+            replaced_by: None,
+        }
+    }
+}
+
+impl Storable for TransactionRecord {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).expect("BUG: failed to encode transaction record"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("BUG: failed to decode transaction record")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+thread_local! {
+    /// Transactions of each principal, keyed by owner and by the position of
+    /// the transaction in the owner's history.
+    static TRANSACTIONS: RefCell<StableBTreeMap<(Principal, u64), TransactionRecord, VMem>> =
+        RefCell::new(StableBTreeMap::init(history_memory()));
+
+    /// Owner and position in the history of the transactions that are final, keyed by
+    /// transaction hash. Pending transactions are looked up in the state instead.
+    static FINAL_TRANSACTIONS: RefCell<StableBTreeMap<String, (Principal, u64), VMem>> =
+        RefCell::new(StableBTreeMap::init(history_index_memory()));
+
+    /// Number of transactions in the history of each principal.
+    static HISTORY_LENGTHS: RefCell<StableBTreeMap<Principal, u64, VMem>> =
+        RefCell::new(StableBTreeMap::init(history_lengths_memory()));
+}
+
+/// Append a transaction to the history of the owner and return its index.
+pub fn record_transaction(owner: Principal, record: TransactionRecord) -> u64 {
+    let index = HISTORY_LENGTHS
+        .with(|lengths| lengths.borrow().get(&owner))
+        .unwrap_or_else(|| history_length(owner));
+    TRANSACTIONS.with(|transactions| transactions.borrow_mut().insert((owner, index), record));
+    HISTORY_LENGTHS.with(|lengths| lengths.borrow_mut().insert(owner, index + 1));
+    index
+}
+
+// Length of a history recorded before the lengths were stored.
+fn history_length(owner: Principal) -> u64 {
+    TRANSACTIONS.with(|transactions| {
+        transactions
+            .borrow()
+            .range((owner, 0)..=(owner, u64::MAX))
+            .last()
+            .map(|((_, index), _)| index + 1)
+            .unwrap_or_default()
+    })
+}
+
+pub fn mark_transaction_replaced(owner: Principal, index: u64, replaced_by: String) {
+    TRANSACTIONS.with(|transactions| {
+        let mut transactions = transactions.borrow_mut();
+        if let Some(mut record) = transactions.get(&(owner, index)) {
+            record.replaced_by = Some(replaced_by);
+            transactions.insert((owner, index), record);
+        }
+    })
+}
+
+pub fn update_transaction_status(owner: Principal, index: u64, status: TransactionStatus) {
+    TRANSACTIONS.with(|transactions| {
+        let mut transactions = transactions.borrow_mut();
+        if let Some(mut record) = transactions.get(&(owner, index)) {
+            record.status = status;
+            transactions.insert((owner, index), record);
+        }
+    })
+}
+
+pub fn get_transaction(owner: Principal, index: u64) -> Option<TransactionRecord> {
+    TRANSACTIONS.with(|transactions| transactions.borrow().get(&(owner, index)))
+}
+
+/// Remember where a transaction that is no longer tracked in the state is in the history.
+pub fn index_final_transaction(tx_hash: String, owner: Principal, index: u64) {
+    FINAL_TRANSACTIONS.with(|final_transactions| {
+        final_transactions
+            .borrow_mut()
+            .insert(tx_hash.to_lowercase(), (owner, index))
+    });
+}
+
+/// Return the owner and history record of a transaction that is final.
+pub fn get_final_transaction(tx_hash: &str) -> Option<(Principal, TransactionRecord)> {
+    let (owner, index) = FINAL_TRANSACTIONS
+        .with(|final_transactions| final_transactions.borrow().get(&tx_hash.to_lowercase()))?;
+    get_transaction(owner, index).map(|record| (owner, record))
+}
+
+/// Return the transactions of the owner, oldest first.
+/// When a chain id is given, only the transactions on that chain are considered.
+pub fn get_transactions(
+    owner: Principal,
+    offset: u64,
+    limit: u64,
+    chain_id: Option<u64>,
+) -> Vec<TransactionRecord> {
+    let limit = limit.min(MAX_TRANSACTIONS_PAGE_SIZE) as usize;
+    TRANSACTIONS.with(|transactions| {
+        let transactions = transactions.borrow();
+        match chain_id {
+            None => transactions
+                .range((owner, offset)..=(owner, u64::MAX))
+                .take(limit)
+                .map(|(_, record)| record)
+                .collect(),
+            Some(chain_id) => transactions
+                .range((owner, 0)..=(owner, u64::MAX))
+                .map(|(_, record)| record)
+                .filter(|record| record.chain_id == chain_id)
+                .skip(offset as usize)
+                .take(limit)
+                .collect(),
+        }
+    })
+}
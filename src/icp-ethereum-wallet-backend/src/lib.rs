@@ -1,26 +1,79 @@
+// This module encodes and decodes contract calls following the Solidity ABI.
+mod abi;
+
+// This module broadcasts signed transactions through several RPC providers.
+mod broadcast;
+
+// This module detects deposits to the addresses of registered principals.
+mod deposits;
+
 // This module handles ECDSA operations for signing Ethereum transactions.
 mod ecdsa;
 
+// This module encodes calls to ERC-20 token contracts.
+mod erc20;
+
+// This module defines the errors returned by the canister's public methods.
+mod error;
+
 // This module provides the EthereumWallet struct and related wallet logic.
 mod ethereum_wallet;
 
+// This module replaces transactions that stay pending for too long with higher fees.
+mod fee_bumper;
+
+// This module estimates transaction fees from the recent fee history.
+mod fees;
+
+// This module prevents concurrent sends from the same principal.
+mod guard;
+
+// This module stores the transaction history of each principal in stable memory.
+mod history;
+
+// This module hands out nonces so that concurrent sends do not collide.
+mod nonce;
+
+// This module notifies subscribed canisters of credited deposits.
+mod notifications;
+
+// This module tracks the receipts of sent transactions.
+mod receipts;
+
+// This module sends raw JSON-RPC requests through the EVM RPC canister.
+mod rpc;
+
 // This module manages the canister's persistent state.
 mod state;
 
+// This module splits stable memory between the data kept across upgrades.
+mod storage;
+
 // Import necessary types and traits from local modules and external crates.
+use crate::abi::{AbiValue, FunctionSignature};
+use crate::deposits::{Deposit, DepositAccount};
+use crate::error::WalletError;
 use crate::ethereum_wallet::EthereumWallet;
-use crate::state::{init_state, read_state};
+use crate::fees::{
+    estimate_gas_limit, estimate_replacement_fees, estimate_transaction_fees, FeeEstimates,
+    ETH_TRANSFER_GAS_LIMIT,
+};
+use crate::guard::SendGuard;
+use crate::history::TransactionRecord;
+use crate::nonce::NonceReservation;
+use crate::receipts::{SentTransaction, TransactionStatus};
+use crate::state::{init_state, mutate_state, read_state, restore_state, save_state};
 use alloy_consensus::{SignableTransaction, TxEip1559, TxEnvelope};
 use alloy_primitives::{hex, Signature, TxKind, U256};
 use candid::{CandidType, Deserialize, Nat, Principal};
 use evm_rpc_canister_types::{
-    BlockTag, EthMainnetService, EthSepoliaService, EvmRpcCanister, GetTransactionCountArgs,
-    GetTransactionCountResult, MultiGetTransactionCountResult, RequestResult, RpcService,
+    BlockTag, EthMainnetService, EthSepoliaService, EvmRpcCanister, GetBlockByNumberResult,
+    GetTransactionCountArgs, GetTransactionCountResult, L2MainnetService,
+    MultiGetBlockByNumberResult, MultiGetTransactionCountResult, RpcApi, RpcService, RpcServices,
 };
 use ic_cdk::api::management_canister::ecdsa::{EcdsaCurve, EcdsaKeyId};
-use ic_cdk::{init, update};
+use ic_cdk::{init, post_upgrade, pre_upgrade, query, update};
 use ic_ethereum_types::Address;
-use num::{BigUint, Num};
 use std::str::FromStr;
 
 // The principal ID of the EVM RPC canister used for Ethereum network interactions.
@@ -32,108 +85,924 @@ pub const EVM_RPC: EvmRpcCanister = EvmRpcCanister(EVM_RPC_CANISTER_ID);
 
 // Canister initialization function, sets up state if provided.
 #[init]
-pub fn init(maybe_init: Option<InitArg>) {
-    if let Some(init_arg) = maybe_init {
-        init_state(init_arg)
+pub fn init(maybe_arg: Option<WalletArg>) {
+    match maybe_arg {
+        Some(WalletArg::Init(init_arg)) => init_state(init_arg),
+        Some(WalletArg::Upgrade(_)) => {
+            ic_cdk::trap("expected an Init argument when installing the canister")
+        }
+        None => {}
+    }
+    setup_timers();
+}
+
+// Save the state to stable memory so that it survives the upgrade.
+#[pre_upgrade]
+fn pre_upgrade() {
+    save_state();
+}
+
+// Restore the state saved by `pre_upgrade` and apply the configuration changes, if any.
+#[post_upgrade]
+fn post_upgrade(maybe_arg: Option<WalletArg>) {
+    restore_state();
+    deposits::index_credited_transfers();
+    match maybe_arg {
+        Some(WalletArg::Upgrade(upgrade_arg)) => mutate_state(|s| s.upgrade(upgrade_arg))
+            .unwrap_or_else(|e| ic_cdk::trap(&format!("invalid upgrade argument: {}", e))),
+        Some(WalletArg::Init(_)) => {
+            ic_cdk::trap("expected an Upgrade argument when upgrading the canister")
+        }
+        None => {}
     }
+    setup_timers();
+}
+
+// Timers are not persisted across upgrades and must be set up again.
+fn setup_timers() {
+    ic_cdk_timers::set_timer_interval(receipts::RECEIPT_POLLING_INTERVAL, || {
+        ic_cdk::spawn(receipts::poll_transaction_receipts())
+    });
+    ic_cdk_timers::set_timer_interval(fee_bumper::FEE_BUMP_INTERVAL, || {
+        ic_cdk::spawn(fee_bumper::bump_stuck_transactions())
+    });
+    ic_cdk_timers::set_timer_interval(deposits::DEPOSIT_SCAN_INTERVAL, || {
+        ic_cdk::spawn(deposits::scan_deposits())
+    });
+    ic_cdk_timers::set_timer_interval(
+        notifications::NOTIFICATION_INTERVAL,
+        notifications::deliver_notifications,
+    );
 }
 
 #[update]
-pub async fn ethereum_address(owner: Option<Principal>) -> String {
-    let caller = validate_caller_not_anonymous();
+pub async fn ethereum_address(owner: Option<Principal>) -> Result<String, WalletError> {
+    let caller = validate_caller_not_anonymous()?;
     let owner = owner.unwrap_or(caller);
-    let wallet = EthereumWallet::new(owner).await;
-    wallet.ethereum_address().to_string()
+    let wallet = EthereumWallet::new(owner).await?;
+    Ok(wallet.ethereum_address().to_string())
 }
 
 
+// Start scanning the Ethereum address of the owner (the caller by default) for incoming
+// ETH and ERC-20 deposits on the chain, and return the address.
+// Deposits are credited once they have the configured number of confirmations.
+// Only controllers can register addresses, since at most `MAX_DEPOSIT_ACCOUNTS_PER_CHAIN`
+// addresses are scanned on each chain.
 #[update]
-pub async fn get_balance(address: Option<String>) -> Nat {
-    let address = address.unwrap_or(ethereum_address(None).await);
+pub async fn register_deposit_address(
+    owner: Option<Principal>,
+    chain_id: Option<u64>,
+) -> Result<String, WalletError> {
+    let caller = validate_caller_not_anonymous()?;
+    if !ic_cdk::api::is_controller(&caller) {
+        return Err(WalletError::Unauthorized(
+            "only controllers can register deposit addresses".to_string(),
+        ));
+    }
+    let owner = owner.unwrap_or(caller);
+    let chain = read_state(|s| s.chain(chain_id))?;
+    let wallet = EthereumWallet::new(owner).await?;
+    let address = wallet.ethereum_address().to_string();
+    mutate_state(|s| {
+        s.register_deposit_account(DepositAccount {
+            owner,
+            chain_id: chain.chain_id(),
+            address: address.clone(),
+            eth_balance: None,
+        })
+    })?;
+    Ok(address)
+}
 
-    let json = format!(
-        r#"{{ "jsonrpc": "2.0", "method": "eth_getBalance", "params": ["{}", "latest"], "id": 1 }}"#,
-        address
-    );
 
-    let max_response_size_bytes = 500_u64;
-    let num_cycles = 1_000_000_000u128;
+// Stop scanning the Ethereum address of the owner for deposits on the chain.
+// Deposits already credited are still tracked until their block is final.
+// Only controllers can unregister addresses.
+#[update]
+pub fn unregister_deposit_address(
+    owner: Principal,
+    chain_id: Option<u64>,
+) -> Result<(), WalletError> {
+    let caller = validate_caller_not_anonymous()?;
+    if !ic_cdk::api::is_controller(&caller) {
+        return Err(WalletError::Unauthorized(
+            "only controllers can unregister deposit addresses".to_string(),
+        ));
+    }
+    let chain_id = read_state(|s| s.chain(chain_id))?.chain_id();
+    mutate_state(|s| s.unregister_deposit_account(chain_id, &owner));
+    Ok(())
+}
 
-    let ethereum_network = read_state(|s| s.ethereum_network());
 
-    let rpc_service = match ethereum_network {
-        EthereumNetwork::Mainnet => RpcService::EthMainnet(EthMainnetService::PublicNode),
-        EthereumNetwork::Sepolia => RpcService::EthSepolia(EthSepoliaService::PublicNode),
+// Return the deposits credited to the owner (the caller by default), oldest first,
+// optionally restricted to one chain.
+// At most `MAX_DEPOSITS_PAGE_SIZE` deposits are returned.
+#[query]
+pub fn get_deposits(
+    owner: Option<Principal>,
+    offset: u64,
+    limit: u64,
+    chain_id: Option<u64>,
+) -> Result<Vec<Deposit>, WalletError> {
+    let owner = match owner {
+        Some(owner) => owner,
+        None => validate_caller_not_anonymous()?,
     };
+    Ok(deposits::get_deposits(owner, offset, limit, chain_id))
+}
 
-    let (response,) = EVM_RPC
-        .request(rpc_service, json, max_response_size_bytes, num_cycles)
-        .await
-        .expect("RPC call failed");
-
-    let hex_balance = match response {
-        RequestResult::Ok(balance_result) => {
-            // The response to a successful `eth_getBalance` call has the following format:
-            // { "id": "[ID]", "jsonrpc": "2.0", "result": "[BALANCE IN HEX]" }
-            let response: serde_json::Value = serde_json::from_str(&balance_result).unwrap();
-            response
-                .get("result")
-                .and_then(|v| v.as_str())
-                .unwrap()
-                .to_string()
-        }
-        RequestResult::Err(e) => panic!("Received an error response: {:?}", e),
+
+// Subscribe a canister to the deposits credited to any registered address.
+// The given method of the canister is called with a `DepositNotification` when a deposit
+// is credited and when its block is finalized or reorged out, until it replies successfully
+// or `MAX_NOTIFICATION_ATTEMPTS` deliveries failed (at-least-once delivery).
+// Only controllers can subscribe canisters, since each delivery costs cycles.
+#[update]
+pub fn subscribe_deposits(subscriber: Principal, method: String) -> Result<(), WalletError> {
+    let caller = validate_caller_not_anonymous()?;
+    if !ic_cdk::api::is_controller(&caller) {
+        return Err(WalletError::Unauthorized(
+            "only controllers can subscribe canisters to deposits".to_string(),
+        ));
+    }
+    // Canister ids are opaque principals, whose last byte is 0x01.
+    if subscriber.as_slice().last() != Some(&0x01) {
+        return Err(WalletError::InvalidArgument(
+            "only canisters can subscribe to deposits".to_string(),
+        ));
+    }
+    if method.is_empty() {
+        return Err(WalletError::InvalidArgument(
+            "the callback method must not be empty".to_string(),
+        ));
+    }
+    mutate_state(|s| s.subscribe(subscriber, method));
+    Ok(())
+}
+
+
+// Stop notifying the subscriber (the calling canister by default) of deposits.
+// Only controllers can unsubscribe another canister.
+// Notifications that were not delivered yet are dropped.
+#[update]
+pub fn unsubscribe_deposits(subscriber: Option<Principal>) -> Result<(), WalletError> {
+    let caller = validate_caller_not_anonymous()?;
+    let subscriber = subscriber.unwrap_or(caller);
+    if subscriber != caller && !ic_cdk::api::is_controller(&caller) {
+        return Err(WalletError::Unauthorized(
+            "only controllers can unsubscribe another canister from deposits".to_string(),
+        ));
+    }
+    mutate_state(|s| s.unsubscribe(&subscriber));
+    Ok(())
+}
+
+
+#[update]
+pub async fn get_balance(
+    address: Option<String>,
+    block: Option<BlockTag>,
+    chain_id: Option<u64>,
+) -> Result<ValueAtBlock, WalletError> {
+    let chain = read_state(|s| s.chain(chain_id))?;
+    let address = match address {
+        Some(address) => parse_address(&address)?,
+        None => parse_address(&ethereum_address(None).await?)?,
     };
+    let (block, block_number) = resolve_block(&chain, block.unwrap_or(BlockTag::Latest)).await?;
+    let value = get_balance_at(&chain, &address, block).await?;
+    Ok(ValueAtBlock {
+        value,
+        block_number,
+    })
+}
 
-    // Remove the "0x" prefix before converting to a decimal number.
-    Nat(BigUint::from_str_radix(&hex_balance[2..], 16).unwrap())
+
+// Fetch the balance of the address at the given block with the consensus of the configured
+// providers. The EVM RPC canister has no typed endpoint for `eth_getBalance`.
+async fn get_balance_at(
+    chain: &ChainConfig,
+    address: &Address,
+    block: BlockTag,
+) -> Result<Nat, WalletError> {
+    let block = pin_block(chain, block).await?;
+    let balance = rpc::multi_json_rpc_request(
+        chain,
+        "eth_getBalance",
+        serde_json::json!([address.to_string(), rpc::block_tag_param(&block)]),
+        500,
+    )
+    .await?;
+    rpc::parse_hex_quantity(&balance)
 }
 
 
 #[update]
-pub async fn transaction_count(owner: Option<Principal>, block: Option<BlockTag>) -> Nat {
-    let caller = validate_caller_not_anonymous();
+pub async fn transaction_count(
+    owner: Option<Principal>,
+    block: Option<BlockTag>,
+    chain_id: Option<u64>,
+) -> Result<ValueAtBlock, WalletError> {
+    let caller = validate_caller_not_anonymous()?;
     let owner = owner.unwrap_or(caller);
-    let wallet = EthereumWallet::new(owner).await;
-    let rpc_services = read_state(|s| s.evm_rpc_services());
+    let chain = read_state(|s| s.chain(chain_id))?;
+    let wallet = EthereumWallet::new(owner).await?;
+    let (block, block_number) =
+        resolve_block(&chain, block.unwrap_or(BlockTag::Finalized)).await?;
+    let value = get_transaction_count(&chain, &wallet.ethereum_address(), block).await?;
+    Ok(ValueAtBlock {
+        value,
+        block_number,
+    })
+}
+
+
+// Resolve a block tag to the number of the block it currently designates, and return the
+// tag to query so that all providers read the same block. The latest block is the one
+// enough providers have reached (see `rpc::latest_block_number`). The pending block is not
+// yet known to the providers by number, so it is queried by tag and reported as the block
+// following the latest one.
+async fn resolve_block(
+    chain: &ChainConfig,
+    block: BlockTag,
+) -> Result<(BlockTag, Nat), WalletError> {
+    let block_number = match &block {
+        BlockTag::Number(number) => return Ok((block.clone(), number.clone())),
+        BlockTag::Earliest => Nat::from(0_u8),
+        BlockTag::Latest | BlockTag::Pending => {
+            let latest = Nat::from(rpc::latest_block_number(chain).await?);
+            if matches!(block, BlockTag::Pending) {
+                return Ok((block, latest + Nat::from(1_u8)));
+            }
+            latest
+        }
+        BlockTag::Safe | BlockTag::Finalized => get_block(chain, block.clone()).await?.0,
+    };
+    Ok((BlockTag::Number(block_number.clone()), block_number))
+}
+
+
+// Pin a block tag to the number of the block it currently designates, see `resolve_block`,
+// so that providers that are ahead or behind all read the same block.
+async fn pin_block(chain: &ChainConfig, block: BlockTag) -> Result<BlockTag, WalletError> {
+    match block {
+        BlockTag::Number(_) | BlockTag::Pending => Ok(block),
+        _ => Ok(resolve_block(chain, block).await?.0),
+    }
+}
+
+
+// Fetch the number and hash of the block designated by the tag with the consensus
+// of the configured providers.
+async fn get_block(chain: &ChainConfig, block: BlockTag) -> Result<(Nat, String), WalletError> {
+    let rpc_services = chain.evm_rpc_services();
+    let evm_rpc_cycles = read_state(|s| s.evm_rpc_cycles());
+    let (result,) = EVM_RPC
+        .eth_get_block_by_number(rpc_services, None, block.clone(), evm_rpc_cycles)
+        .await
+        .map_err(|e| {
+            WalletError::RpcError(format!("failed to get block {:?}, error: {:?}", block, e))
+        })?;
+    match result {
+        MultiGetBlockByNumberResult::Consistent(consistent_result) => match consistent_result {
+            GetBlockByNumberResult::Ok(block) => Ok((block.number, block.hash)),
+            GetBlockByNumberResult::Err(error) => Err(WalletError::RpcError(format!(
+                "failed to get block {:?}, error: {:?}",
+                block, error
+            ))),
+        },
+        MultiGetBlockByNumberResult::Inconsistent(inconsistent_results) => chain
+            .consensus_strategy()
+            .reduce(
+                inconsistent_results
+                    .iter()
+                    .map(|(_, result)| match result {
+                        GetBlockByNumberResult::Ok(block) => {
+                            Some((block.number.clone(), block.hash.clone()))
+                        }
+                        GetBlockByNumberResult::Err(_) => None,
+                    })
+                    .collect(),
+            )
+            .ok_or_else(|| {
+                WalletError::RpcInconsistent(format!(
+                    "inconsistent results when retrieving block {:?}. Received results: {:?}",
+                    block, inconsistent_results
+                ))
+            }),
+    }
+}
+
+
+// Fetch the transaction count of the address at the given block with the consensus
+// of the configured providers.
+async fn get_transaction_count(
+    chain: &ChainConfig,
+    address: &Address,
+    block: BlockTag,
+) -> Result<Nat, WalletError> {
+    let rpc_services = chain.evm_rpc_services();
+    let evm_rpc_cycles = read_state(|s| s.evm_rpc_cycles());
     let args = GetTransactionCountArgs {
-        address: wallet.ethereum_address().to_string(),
-        block: block.unwrap_or(BlockTag::Finalized),
+        address: address.to_string(),
+        block: pin_block(chain, block).await?,
     };
     let (result,) = EVM_RPC
-        .eth_get_transaction_count(rpc_services, None, args.clone(), 2_000_000_000_u128)
+        .eth_get_transaction_count(rpc_services, None, args.clone(), evm_rpc_cycles)
         .await
-        .unwrap_or_else(|e| {
-            panic!(
+        .map_err(|e| {
+            WalletError::RpcError(format!(
                 "failed to get transaction count for {:?}, error: {:?}",
                 args, e
-            )
-        });
+            ))
+        })?;
     match result {
         MultiGetTransactionCountResult::Consistent(consistent_result) => match consistent_result {
-            GetTransactionCountResult::Ok(count) => count,
-            GetTransactionCountResult::Err(error) => {
-                ic_cdk::trap(&format!("failed to get transaction count for {:?}, error: {:?}",args, error))
-            }
+            GetTransactionCountResult::Ok(count) => Ok(count),
+            GetTransactionCountResult::Err(error) => Err(WalletError::RpcError(format!(
+                "failed to get transaction count for {:?}, error: {:?}",
+                args, error
+            ))),
         },
-        MultiGetTransactionCountResult::Inconsistent(inconsistent_results) => {
-            ic_cdk::trap(&format!("inconsistent results when retrieving transaction count for {:?}. Received results: {:?}", args, inconsistent_results))
-        }
+        MultiGetTransactionCountResult::Inconsistent(inconsistent_results) => chain
+            .consensus_strategy()
+            .reduce(
+                inconsistent_results
+                    .iter()
+                    .map(|(_, result)| match result {
+                        GetTransactionCountResult::Ok(count) => Some(count.clone()),
+                        GetTransactionCountResult::Err(_) => None,
+                    })
+                    .collect(),
+            )
+            .ok_or_else(|| {
+                WalletError::RpcInconsistent(format!(
+                    "inconsistent results when retrieving transaction count for {:?}. Received results: {:?}",
+                    args, inconsistent_results
+                ))
+            }),
     }
 }
 
 
 #[update]
-pub async fn send_eth(to: String, amount: Nat) -> String {
-    use alloy_eips::eip2718::Encodable2718;
+pub async fn send_eth(
+    to: String,
+    amount: Nat,
+    chain_id: Option<u64>,
+) -> Result<String, WalletError> {
+    send_transaction(TransactionRequest {
+        to,
+        value: amount,
+        chain_id,
+        ..Default::default()
+    })
+    .await
+}
+
+
+#[update]
+pub async fn send_erc20(
+    token: String,
+    to: String,
+    amount: Nat,
+    chain_id: Option<u64>,
+) -> Result<String, WalletError> {
+    let to_address = parse_recipient(&to)?;
+    let input = erc20::encode_transfer(&to_address, nat_to_u256(amount)?);
+    send_transaction(TransactionRequest {
+        to: token,
+        data: Some(format!("0x{}", hex::encode(input))),
+        chain_id,
+        ..Default::default()
+    })
+    .await
+}
+
+
+#[update]
+pub async fn send_transaction(request: TransactionRequest) -> Result<String, WalletError> {
+    let caller = validate_caller_not_anonymous()?;
+    let chain = read_state(|s| s.chain(request.chain_id))?;
+    let _guard = SendGuard::new(caller)?;
+    let wallet = EthereumWallet::new(caller).await?;
+    let (transaction, nonce_reservation) = prepare_transaction(&chain, &wallet, request).await?;
+    let (tx_hash, raw_transaction_hex) = sign_transaction(&wallet, transaction.clone()).await?;
+    let history_index = record_signed_transaction(
+        &wallet,
+        &transaction,
+        &tx_hash,
+        raw_transaction_hex.clone(),
+        None,
+    );
+    broadcast_signed_transaction(
+        &chain,
+        &wallet,
+        &transaction,
+        history_index,
+        &raw_transaction_hex,
+    )
+    .await?;
+    if let Some(nonce_reservation) = nonce_reservation {
+        nonce_reservation.confirm();
+    }
+    track_sent_transaction(
+        &wallet,
+        &transaction,
+        &tx_hash,
+        history_index,
+        0,
+        transaction.max_fee_per_gas,
+    );
+    Ok(tx_hash)
+}
+
+// Re-send a pending transaction with the same nonce and higher fees.
+#[update]
+pub async fn speed_up(tx_hash: String) -> Result<String, WalletError> {
+    replace_transaction(tx_hash, Replacement::SpeedUp).await
+}
+
+// Replace a pending transaction with a zero-value transfer to the wallet itself,
+// so that the original transaction can no longer be mined.
+#[update]
+pub async fn cancel(tx_hash: String) -> Result<String, WalletError> {
+    replace_transaction(tx_hash, Replacement::Cancel).await
+}
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+enum Replacement {
+    SpeedUp,
+    Cancel,
+    /// Speed-up by the fee bumper, counted against `max_fee_bumps`.
+    FeeBump,
+}
+
+async fn replace_transaction(
+    tx_hash: String,
+    replacement: Replacement,
+) -> Result<String, WalletError> {
+    let caller = validate_caller_not_anonymous()?;
+    replace_sent_transaction(caller, &tx_hash, replacement).await
+}
+
+// Sign and send a transaction with the same nonce as a pending transaction of the owner.
+// Also used by the fee bumper.
+async fn replace_sent_transaction(
+    owner: Principal,
+    tx_hash: &str,
+    replacement: Replacement,
+) -> Result<String, WalletError> {
+    let _guard = SendGuard::new(owner)?;
+    let tx_hash = tx_hash.to_lowercase();
+    let sent_transaction = match read_state(|s| s.sent_transaction(&tx_hash)) {
+        Some(tx) if tx.owner == owner => tx,
+        _ => {
+            return Err(match history::get_final_transaction(&tx_hash) {
+                Some((tx_owner, record)) if tx_owner == owner => {
+                    WalletError::TransactionNotPending {
+                        tx_hash,
+                        status: record.status,
+                    }
+                }
+                _ => WalletError::TransactionNotFound(tx_hash),
+            })
+        }
+    };
+    if sent_transaction.status != TransactionStatus::Pending {
+        return Err(WalletError::TransactionNotPending {
+            tx_hash,
+            status: sent_transaction.status,
+        });
+    }
+    if let Some(replaced_by) = sent_transaction.replaced_by {
+        return Err(WalletError::InvalidArgument(format!(
+            "transaction {} was already replaced by {}",
+            tx_hash, replaced_by
+        )));
+    }
+    let record = history::get_transaction(owner, sent_transaction.history_index)
+        .ok_or_else(|| WalletError::TransactionNotFound(tx_hash.clone()))?;
+    let previous = decode_raw_transaction(&record.raw_transaction)?;
+    let chain = read_state(|s| s.chain(Some(sent_transaction.chain_id)))?;
+
+    let wallet = EthereumWallet::new(owner).await?;
+    if !sent_transaction
+        .from
+        .eq_ignore_ascii_case(&wallet.ethereum_address().to_string())
+    {
+        return Err(WalletError::InvalidArgument(format!(
+            "transaction {} was sent from {}, an address derived from a previous ECDSA key",
+            tx_hash, sent_transaction.from
+        )));
+    }
+    let initial_max_fee_per_gas = match sent_transaction.initial_max_fee_per_gas {
+        Some(fee) => nat_to_u128(fee)?,
+        None => previous.max_fee_per_gas,
+    };
+    let (fee_bumps, max_fee_per_gas_cap) = match replacement {
+        Replacement::FeeBump => (
+            sent_transaction.fee_bumps.saturating_add(1),
+            initial_max_fee_per_gas.saturating_mul(fee_bumper::MAX_FEE_BUMP_FACTOR),
+        ),
+        Replacement::SpeedUp | Replacement::Cancel => (sent_transaction.fee_bumps, u128::MAX),
+    };
+    let fees = estimate_replacement_fees(
+        &chain,
+        FeeEstimates {
+            max_fee_per_gas: previous.max_fee_per_gas,
+            max_priority_fee_per_gas: previous.max_priority_fee_per_gas,
+        },
+        max_fee_per_gas_cap,
+    )
+    .await?;
+    let mut transaction = TxEip1559 {
+        max_fee_per_gas: fees.max_fee_per_gas,
+        max_priority_fee_per_gas: fees.max_priority_fee_per_gas,
+        ..previous
+    };
+    if replacement == Replacement::Cancel {
+        transaction.to = TxKind::Call(alloy_primitives::Address::from(
+            wallet.ethereum_address().into_bytes(),
+        ));
+        transaction.value = U256::ZERO;
+        transaction.input = Default::default();
+        transaction.gas_limit = ETH_TRANSFER_GAS_LIMIT;
+    }
+
+    let (new_tx_hash, raw_transaction_hex) = sign_transaction(&wallet, transaction.clone()).await?;
+    let history_index = record_signed_transaction(
+        &wallet,
+        &transaction,
+        &new_tx_hash,
+        raw_transaction_hex.clone(),
+        Some(tx_hash.clone()),
+    );
+    broadcast_signed_transaction(
+        &chain,
+        &wallet,
+        &transaction,
+        history_index,
+        &raw_transaction_hex,
+    )
+    .await?;
+    ic_cdk::println!(
+        "Replaced transaction {} with {} ({:?})",
+        tx_hash,
+        new_tx_hash,
+        replacement
+    );
+    track_sent_transaction(
+        &wallet,
+        &transaction,
+        &new_tx_hash,
+        history_index,
+        fee_bumps,
+        initial_max_fee_per_gas,
+    );
+    mutate_state(|s| s.mark_transaction_replaced(&tx_hash, new_tx_hash.clone()));
+    history::mark_transaction_replaced(owner, sent_transaction.history_index, new_tx_hash.clone());
+    Ok(new_tx_hash)
+}
+
+// Decode a hex encoded signed EIP-1559 transaction.
+fn decode_raw_transaction(raw_transaction_hex: &str) -> Result<TxEip1559, WalletError> {
+    use alloy_eips::eip2718::Decodable2718;
+
+    let bytes = hex::decode(raw_transaction_hex).map_err(|e| {
+        WalletError::InvalidArgument(format!("invalid raw transaction hex: {}", e))
+    })?;
+    match TxEnvelope::decode_2718(&mut bytes.as_slice()) {
+        Ok(TxEnvelope::Eip1559(signed_tx)) => Ok(signed_tx.tx().clone()),
+        Ok(envelope) => Err(WalletError::InvalidArgument(format!(
+            "unsupported transaction type {:?}",
+            envelope.tx_type()
+        ))),
+        Err(e) => Err(WalletError::InvalidArgument(format!(
+            "failed to decode raw transaction: {}",
+            e
+        ))),
+    }
+}
 
-    let caller = validate_caller_not_anonymous();
-    let _to_address = Address::from_str(&to).unwrap_or_else(|e| {
-        ic_cdk::trap(&format!("failed to parse the recipient address: {:?}", e))
+// Add a transaction that was just signed to the owner's history, before it is sent,
+// so that it appears there even if sending it fails. Returns its index in the history.
+fn record_signed_transaction(
+    wallet: &EthereumWallet,
+    transaction: &TxEip1559,
+    tx_hash: &str,
+    raw_transaction_hex: String,
+    replaces: Option<String>,
+) -> u64 {
+    history::record_transaction(
+        wallet.owner(),
+        TransactionRecord {
+            replaces,
+            ..TransactionRecord::new(
+                transaction,
+                tx_hash.to_string(),
+                raw_transaction_hex,
+                ic_cdk::api::time(),
+            )
+        },
+    )
+}
+
+// Send a signed transaction recorded in the owner's history.
+// Only a rejection of the transaction itself proves that it was not sent: its status in the
+// history is then updated accordingly and the error returned. A nonce rejected by every
+// provider is such a rejection once the nonce was used by a mined transaction. Any other
+// failure is ambiguous, e.g. a provider that timed out after forwarding the transaction.
+// The transaction is then treated as sent and the receipt polling settles its outcome,
+// sending it again if needed.
+async fn broadcast_signed_transaction(
+    chain: &ChainConfig,
+    wallet: &EthereumWallet,
+    transaction: &TxEip1559,
+    history_index: u64,
+    raw_transaction_hex: &str,
+) -> Result<(), WalletError> {
+    let rejection = match broadcast::broadcast_raw_transaction(chain, raw_transaction_hex).await {
+        Ok(()) => return Ok(()),
+        Err(WalletError::InsufficientFunds) => WalletError::InsufficientFunds,
+        Err(WalletError::NonceTooLow) => {
+            match get_transaction_count(chain, &wallet.ethereum_address(), BlockTag::Latest)
+                .await
+                .and_then(nat_to_u64)
+            {
+                Ok(latest_count) if latest_count > transaction.nonce => WalletError::NonceTooLow,
+                result => {
+                    ic_cdk::println!(
+                        "Nonce {} of raw transaction {} was rejected as too low but is not mined yet ({:?}), tracking it as pending",
+                        transaction.nonce,
+                        raw_transaction_hex,
+                        result
+                    );
+                    return Ok(());
+                }
+            }
+        }
+        Err(e) => {
+            ic_cdk::println!(
+                "Outcome of broadcasting raw transaction {} is unknown, tracking it as pending: {:?}",
+                raw_transaction_hex,
+                e
+            );
+            return Ok(());
+        }
+    };
+    history::update_transaction_status(
+        wallet.owner(),
+        history_index,
+        TransactionStatus::Rejected {
+            reason: format!("{:?}", rejection),
+        },
+    );
+    Err(rejection)
+}
+
+// Track the receipt of a transaction that was just sent.
+fn track_sent_transaction(
+    wallet: &EthereumWallet,
+    transaction: &TxEip1559,
+    tx_hash: &str,
+    history_index: u64,
+    fee_bumps: u8,
+    initial_max_fee_per_gas: u128,
+) {
+    let owner = wallet.owner();
+    let sent_at = ic_cdk::api::time();
+    mutate_state(|s| {
+        s.record_sent_transaction(
+            tx_hash.to_string(),
+            SentTransaction {
+                owner,
+                chain_id: transaction.chain_id,
+                from: wallet.ethereum_address().to_string(),
+                nonce: transaction.nonce,
+                sent_at,
+                status: TransactionStatus::Pending,
+                history_index,
+                fee_bumps,
+                initial_max_fee_per_gas: Some(Nat::from(initial_max_fee_per_gas)),
+                replaced_by: None,
+                pending_since_block: None,
+                finalized_at_block: None,
+            },
+        )
     });
-    let chain_id = read_state(|s| s.ethereum_network().chain_id());
-    let nonce = nat_to_u64(transaction_count(Some(caller), Some(BlockTag::Latest)).await);
-    let (gas_limit, max_fee_per_gas, max_priority_fee_per_gas) = estimate_transaction_fees();
+}
+
+
+#[query]
+pub fn transaction_status(tx_hash: String) -> Option<TransactionStatus> {
+    read_state(|s| s.transaction_status(&tx_hash))
+        .or_else(|| history::get_final_transaction(&tx_hash).map(|(_, record)| record.status))
+}
+
+// Return a page of the transactions signed on behalf of the owner, oldest first,
+// optionally restricted to one chain.
+// At most `MAX_TRANSACTIONS_PAGE_SIZE` transactions are returned.
+#[query]
+pub fn get_transactions(
+    owner: Option<Principal>,
+    offset: u64,
+    limit: u64,
+    chain_id: Option<u64>,
+) -> Result<Vec<TransactionRecord>, WalletError> {
+    let owner = match owner {
+        Some(owner) => owner,
+        None => validate_caller_not_anonymous()?,
+    };
+    Ok(history::get_transactions(owner, offset, limit, chain_id))
+}
+
+
+#[update]
+pub async fn erc20_balance(
+    token: String,
+    owner: Option<Principal>,
+    chain_id: Option<u64>,
+) -> Result<Nat, WalletError> {
+    let caller = validate_caller_not_anonymous()?;
+    let owner = owner.unwrap_or(caller);
+    let token_address = parse_address(&token)?;
+    let chain = read_state(|s| s.chain(chain_id))?;
+    let wallet = EthereumWallet::new(owner).await?;
+    let calldata = erc20::encode_balance_of(&wallet.ethereum_address());
+    erc20_call(&chain, &token_address, calldata).await
+}
+
+
+#[update]
+pub async fn erc20_allowance(
+    token: String,
+    owner: Option<Principal>,
+    spender: String,
+    chain_id: Option<u64>,
+) -> Result<Nat, WalletError> {
+    let caller = validate_caller_not_anonymous()?;
+    let owner = owner.unwrap_or(caller);
+    let token_address = parse_address(&token)?;
+    let spender_address = parse_address(&spender)?;
+    let chain = read_state(|s| s.chain(chain_id))?;
+    let wallet = EthereumWallet::new(owner).await?;
+    let calldata = erc20::encode_allowance(&wallet.ethereum_address(), &spender_address);
+    erc20_call(&chain, &token_address, calldata).await
+}
+
+
+// Perform an `eth_call` returning a single `uint256` on the token contract,
+// requiring all providers to agree on the result.
+async fn erc20_call(
+    chain: &ChainConfig,
+    token: &Address,
+    calldata: Vec<u8>,
+) -> Result<Nat, WalletError> {
+    let return_data = call_contract(chain, token, &calldata, &BlockTag::Latest).await?;
+    erc20::decode_uint256(&return_data).map_err(|e| {
+        WalletError::RpcError(format!(
+            "failed to decode result of eth_call to {}: {}",
+            token, e
+        ))
+    })
+}
+
+
+#[update]
+pub async fn eth_call(
+    to: String,
+    data: String,
+    block: Option<BlockTag>,
+    chain_id: Option<u64>,
+) -> Result<String, WalletError> {
+    validate_caller_not_anonymous()?;
+    let chain = read_state(|s| s.chain(chain_id))?;
+    let to_address = parse_address(&to)?;
+    let calldata = parse_calldata(&data)?;
+    let return_data = call_contract(
+        &chain,
+        &to_address,
+        &calldata,
+        &block.unwrap_or(BlockTag::Latest),
+    )
+    .await?;
+    Ok(format!("0x{}", hex::encode(return_data)))
+}
+
+
+#[update]
+pub async fn eth_call_decoded(
+    to: String,
+    signature: String,
+    args: Vec<AbiValue>,
+    block: Option<BlockTag>,
+    chain_id: Option<u64>,
+) -> Result<Vec<AbiValue>, WalletError> {
+    validate_caller_not_anonymous()?;
+    let chain = read_state(|s| s.chain(chain_id))?;
+    let to_address = parse_address(&to)?;
+    let function = FunctionSignature::from_str(&signature).map_err(|e| {
+        WalletError::InvalidArgument(format!("failed to parse the function signature: {}", e))
+    })?;
+    let calldata = function.encode_call(&args).map_err(|e| {
+        WalletError::InvalidArgument(format!(
+            "failed to encode the arguments of {}: {}",
+            signature, e
+        ))
+    })?;
+    let return_data = call_contract(
+        &chain,
+        &to_address,
+        &calldata,
+        &block.unwrap_or(BlockTag::Latest),
+    )
+    .await?;
+    function.decode_output(&return_data).map_err(|e| {
+        WalletError::RpcError(format!("failed to decode the result of {}: {}", signature, e))
+    })
+}
+
+
+// Perform an `eth_call` at the given block, requiring all providers to agree on the
+// returned data.
+async fn call_contract(
+    chain: &ChainConfig,
+    to: &Address,
+    calldata: &[u8],
+    block: &BlockTag,
+) -> Result<Vec<u8>, WalletError> {
+    /// Generous bound on the size of the returned data for reads of contract state.
+    const MAX_RESPONSE_SIZE_BYTES: u64 = 10_000;
+
+    let block = pin_block(chain, block.clone()).await?;
+    let params = serde_json::json!([
+        {
+            "to": to.to_string(),
+            "data": format!("0x{}", hex::encode(calldata)),
+        },
+        rpc::block_tag_param(&block)
+    ]);
+    let result =
+        rpc::multi_json_rpc_request(chain, "eth_call", params, MAX_RESPONSE_SIZE_BYTES).await?;
+    result
+        .as_str()
+        .and_then(|data| hex::decode(data).ok())
+        .ok_or_else(|| WalletError::RpcError(format!("invalid eth_call result {}", result)))
+}
+
+
+// Build an EIP-1559 transaction sent from the wallet. The nonce, gas limit and fees
+// are fetched from the network unless overridden in the request.
+// A nonce that is not overridden is reserved and given back unless the reservation is confirmed.
+async fn prepare_transaction(
+    chain: &ChainConfig,
+    wallet: &EthereumWallet,
+    request: TransactionRequest,
+) -> Result<(TxEip1559, Option<NonceReservation>), WalletError> {
+    let to = parse_recipient(&request.to)?;
+    let value = nat_to_u256(request.value)?;
+    let input = match request.data {
+        Some(data) => parse_calldata(&data)?,
+        None => Vec::new(),
+    };
+    let chain_id = chain.chain_id();
+    let (nonce, nonce_reservation) = match request.nonce {
+        Some(nonce) => (nat_to_u64(nonce)?, None),
+        None => {
+            let reservation = reserve_nonce(chain, &wallet.ethereum_address()).await?;
+            (reservation.nonce(), Some(reservation))
+        }
+    };
+    let gas_limit = match request.gas_limit {
+        Some(gas_limit) => nat_to_u128(gas_limit)?,
+        None => {
+            estimate_gas_limit(chain, &wallet.ethereum_address(), &to, value, &input).await?
+        }
+    };
+    let (max_fee_per_gas, max_priority_fee_per_gas) =
+        match (request.max_fee_per_gas, request.max_priority_fee_per_gas) {
+            (Some(max_fee_per_gas), Some(max_priority_fee_per_gas)) => {
+                (nat_to_u128(max_fee_per_gas)?, nat_to_u128(max_priority_fee_per_gas)?)
+            }
+            (max_fee_per_gas, max_priority_fee_per_gas) => {
+                let estimates: FeeEstimates = estimate_transaction_fees(chain).await?;
+                (
+                    max_fee_per_gas
+                        .map_or(Ok(estimates.max_fee_per_gas), nat_to_u128)?,
+                    max_priority_fee_per_gas
+                        .map_or(Ok(estimates.max_priority_fee_per_gas), nat_to_u128)?,
+                )
+            }
+        };
+    if max_priority_fee_per_gas > max_fee_per_gas {
+        return Err(WalletError::InvalidArgument(format!(
+            "max priority fee per gas {} exceeds max fee per gas {}",
+            max_priority_fee_per_gas, max_fee_per_gas
+        )));
+    }
 
     let transaction = TxEip1559 {
         chain_id,
@@ -141,15 +1010,56 @@ pub async fn send_eth(to: String, amount: Nat) -> String {
         gas_limit,
         max_fee_per_gas,
         max_priority_fee_per_gas,
-        to: TxKind::Call(to.parse().expect("failed to parse recipient address")),
-        value: nat_to_u256(amount),
+        to: TxKind::Call(alloy_primitives::Address::from(to.into_bytes())),
+        value,
         access_list: Default::default(),
-        input: Default::default(),
+        input: input.into(),
+    };
+    Ok((transaction, nonce_reservation))
+}
+
+
+// Reserve the next nonce of the address, reconciled with its on-chain transaction counts.
+// The pending count is only best effort since providers' mempools often differ.
+async fn reserve_nonce(
+    chain: &ChainConfig,
+    address: &Address,
+) -> Result<NonceReservation, WalletError> {
+    let latest_count =
+        nat_to_u64(get_transaction_count(chain, address, BlockTag::Latest).await?)?;
+    // Without the pending count, nonces still in the mempool cannot be told apart from
+    // dropped ones, so no gap is detected.
+    let pending_count = match get_transaction_count(chain, address, BlockTag::Pending).await {
+        Ok(count) => Some(nat_to_u64(count)?.max(latest_count)),
+        Err(e) => {
+            ic_cdk::println!(
+                "Failed to get pending transaction count of {}, using latest count {}: {:?}",
+                address,
+                latest_count,
+                e
+            );
+            None
+        }
     };
+    Ok(NonceReservation::new(
+        chain.chain_id(),
+        *address,
+        latest_count,
+        pending_count,
+    ))
+}
+
+
+// Sign the transaction with the wallet's threshold ECDSA key.
+// Returns the transaction hash and the hex encoded signed transaction.
+async fn sign_transaction(
+    wallet: &EthereumWallet,
+    transaction: TxEip1559,
+) -> Result<(String, String), WalletError> {
+    use alloy_eips::eip2718::Encodable2718;
 
-    let wallet = EthereumWallet::new(caller).await;
     let tx_hash = transaction.signature_hash().0;
-    let (raw_signature, recovery_id) = wallet.sign_with_ecdsa(tx_hash).await;
+    let (raw_signature, recovery_id) = wallet.sign_with_ecdsa(tx_hash).await?;
     let signature = Signature::from_bytes_and_parity(&raw_signature, recovery_id.is_y_odd())
         .expect("BUG: failed to create a signature");
     let signed_tx = transaction.into_signed(signature);
@@ -159,69 +1069,434 @@ pub async fn send_eth(to: String, amount: Nat) -> String {
     TxEnvelope::from(signed_tx).encode_2718(&mut tx_bytes);
     let raw_transaction_hex = format!("0x{}", hex::encode(&tx_bytes));
     ic_cdk::println!(
-        "Sending raw transaction hex {} with transaction hash {}",
+        "Signed raw transaction hex {} with transaction hash {}",
         raw_transaction_hex,
         raw_transaction_hash
     );
-    // The canister is sending a signed statement, meaning a malicious provider could only affect availability.
-    // For demonstration purposes, the canister uses a single provider to send the signed transaction,
-    // but in production multiple providers (e.g., using a round-robin strategy) should be used to avoid a single point of failure.
-    let single_rpc_service = read_state(|s| s.single_evm_rpc_service());
-    let (result,) = EVM_RPC
-        .eth_send_raw_transaction(
-            single_rpc_service,
-            None,
-            raw_transaction_hex.clone(),
-            2_000_000_000_u128,
-        )
-        .await
-        .unwrap_or_else(|e| {
-            panic!(
-                "failed to send raw transaction {}, error: {:?}",
-                raw_transaction_hex, e
-            )
-        });
-
-    ic_cdk::println!(
-        "Result of sending raw transaction {}: {:?}. \
-    Due to the replicated nature of HTTPs outcalls, an error such as transaction already known or nonce too low could be reported, \
-    even though the transaction was successfully sent. \
-    Check whether the transaction appears on Etherscan or check that the transaction count on \
-    that address at latest block height did increase.",
-        raw_transaction_hex,
-        result
-    );
-
-    raw_transaction_hash.to_string()
+    Ok((raw_transaction_hash.to_string(), raw_transaction_hex))
 }
 
+/// A value read from the chain together with the number of the block it was read at.
+#[derive(CandidType, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct ValueAtBlock {
+    pub value: Nat,
+    pub block_number: Nat,
+}
 
-fn estimate_transaction_fees() -> (u128, u128, u128) {
-    /// Standard gas limit for an Ethereum transfer to an EOA.
-    /// Other transactions, in particular ones interacting with a smart contract (e.g., ERC-20), would require a higher gas limit.
-    const GAS_LIMIT: u128 = 21_000;
+/// An arbitrary transaction to be signed by the caller's wallet.
+/// Fields left empty are filled in from the network (nonce, gas limit and fees).
+#[derive(CandidType, Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+pub struct TransactionRequest {
+    /// Recipient or contract address.
+    pub to: String,
+    /// Amount of wei sent along with the transaction.
+    pub value: Nat,
+    /// Hex-encoded calldata.
+    pub data: Option<String>,
+    pub gas_limit: Option<Nat>,
+    pub max_fee_per_gas: Option<Nat>,
+    pub max_priority_fee_per_gas: Option<Nat>,
+    pub nonce: Option<Nat>,
+    /// Chain to send the transaction on. Defaults to the chain the canister was deployed for.
+    pub chain_id: Option<u64>,
+}
 
-    /// Very crude estimates of max_fee_per_gas and max_priority_fee_per_gas.
-    /// A real world application would need to estimate this more accurately by for example fetching the fee history from the last 5 blocks.
-    const MAX_FEE_PER_GAS: u128 = 50_000_000_000;
-    const MAX_PRIORITY_FEE_PER_GAS: u128 = 1_500_000_000;
-    (GAS_LIMIT, MAX_FEE_PER_GAS, MAX_PRIORITY_FEE_PER_GAS)
+/// Argument of the canister when it is installed or upgraded.
+#[derive(CandidType, Deserialize, Debug, PartialEq, Eq)]
+pub enum WalletArg {
+    Init(InitArg),
+    Upgrade(UpgradeArg),
 }
 
 #[derive(CandidType, Deserialize, Debug, Default, PartialEq, Eq)]
 pub struct InitArg {
     pub ethereum_network: Option<EthereumNetwork>,
     pub ecdsa_key_name: Option<EcdsaKeyName>,
+    /// Upper bound on the estimated max fee per gas, in wei.
+    pub max_fee_per_gas_ceiling: Option<Nat>,
+    /// Percentage added on top of the `eth_estimateGas` result when setting the gas limit.
+    pub gas_limit_buffer_percent: Option<u8>,
+    /// RPC providers to query, in order of preference. Defaults to three providers of the network.
+    pub rpc_providers: Option<Vec<RpcProvider>>,
+    /// JSON-RPC endpoints to query instead of `rpc_providers`.
+    pub custom_rpc_services: Option<Vec<RpcApi>>,
+    /// How to reconcile different results from the providers. Defaults to `Equality`.
+    pub consensus_strategy: Option<ConsensusStrategy>,
+    /// How signed transactions are sent to the providers. Defaults to `RoundRobin`.
+    pub broadcast_strategy: Option<BroadcastStrategy>,
+    /// Cycles attached to each call to the EVM RPC canister.
+    pub evm_rpc_cycles: Option<Nat>,
+    /// Replace transactions still pending after this many blocks with higher fees.
+    /// Automatic fee bumping is disabled when unset or zero.
+    pub fee_bump_after_blocks: Option<u64>,
+    /// Maximum number of automatic fee bumps of a single transaction.
+    pub max_fee_bumps: Option<u8>,
+    /// Number of blocks on top of the block of a deposit before it is credited. Defaults to 12.
+    pub deposit_confirmations: Option<u64>,
+    /// Block from which deposits and mined transactions can no longer be reorged.
+    /// Until then, they are checked again against the canonical chain. Defaults to `Finalized`.
+    pub finality_tag: Option<FinalityTag>,
+    /// Other chains to operate on besides `ethereum_network`, selected by chain id in each call.
+    /// The Ethereum address of a user is the same on every chain.
+    pub additional_chains: Option<Vec<ChainConfig>>,
 }
 
+/// Changes to the configuration applied when upgrading the canister.
+/// Fields left empty keep their current value.
+#[derive(CandidType, Deserialize, Debug, Default, PartialEq, Eq)]
+pub struct UpgradeArg {
+    pub ethereum_network: Option<EthereumNetwork>,
+    pub ecdsa_key_name: Option<EcdsaKeyName>,
+    pub max_fee_per_gas_ceiling: Option<Nat>,
+    pub gas_limit_buffer_percent: Option<u8>,
+    pub rpc_providers: Option<Vec<RpcProvider>>,
+    pub custom_rpc_services: Option<Vec<RpcApi>>,
+    pub consensus_strategy: Option<ConsensusStrategy>,
+    pub broadcast_strategy: Option<BroadcastStrategy>,
+    pub evm_rpc_cycles: Option<Nat>,
+    /// Set to zero to disable automatic fee bumping.
+    pub fee_bump_after_blocks: Option<u64>,
+    pub max_fee_bumps: Option<u8>,
+    pub deposit_confirmations: Option<u64>,
+    pub finality_tag: Option<FinalityTag>,
+    /// Replaces all additional chains when set.
+    pub additional_chains: Option<Vec<ChainConfig>>,
+    /// Changing the ECDSA key changes every user's Ethereum address and is refused unless this is set.
+    pub allow_address_change: Option<bool>,
+    /// Removing a chain drops its pending transactions and deposit addresses and is refused
+    /// unless this is set.
+    pub allow_chain_removal: Option<bool>,
+}
+
+/// An RPC provider supported by the EVM RPC canister.
+#[derive(CandidType, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+pub enum RpcProvider {
+    Alchemy,
+    Ankr,
+    BlockPi,
+    Cloudflare,
+    PublicNode,
+    Sepolia,
+}
+
+#[derive(CandidType, Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+pub enum EthereumNetwork {
+    Mainnet,
+    #[default]
+    Sepolia,
+    ArbitrumOne,
+    BaseMainnet,
+    OptimismMainnet,
+    /// Any other EVM chain, reached through the given JSON-RPC endpoints.
+    Custom {
+        chain_id: u64,
+        rpc_services: Vec<RpcApi>,
+    },
+}
 
 impl EthereumNetwork {
     pub fn chain_id(&self) -> u64 {
         match self {
             EthereumNetwork::Mainnet => 1,
             EthereumNetwork::Sepolia => 11155111,
+            EthereumNetwork::ArbitrumOne => 42161,
+            EthereumNetwork::BaseMainnet => 8453,
+            EthereumNetwork::OptimismMainnet => 10,
+            EthereumNetwork::Custom { chain_id, .. } => *chain_id,
+        }
+    }
+
+    /// Return the EVM RPC canister service of the provider on this network,
+    /// or `None` if the provider does not support the network.
+    pub fn rpc_service(&self, provider: RpcProvider) -> Option<RpcService> {
+        match self {
+            EthereumNetwork::Mainnet => match provider {
+                RpcProvider::Alchemy => Some(EthMainnetService::Alchemy),
+                RpcProvider::Ankr => Some(EthMainnetService::Ankr),
+                RpcProvider::BlockPi => Some(EthMainnetService::BlockPi),
+                RpcProvider::Cloudflare => Some(EthMainnetService::Cloudflare),
+                RpcProvider::PublicNode => Some(EthMainnetService::PublicNode),
+                RpcProvider::Sepolia => None,
+            }
+            .map(RpcService::EthMainnet),
+            EthereumNetwork::Sepolia => match provider {
+                RpcProvider::Alchemy => Some(EthSepoliaService::Alchemy),
+                RpcProvider::Ankr => Some(EthSepoliaService::Ankr),
+                RpcProvider::BlockPi => Some(EthSepoliaService::BlockPi),
+                RpcProvider::Cloudflare => None,
+                RpcProvider::PublicNode => Some(EthSepoliaService::PublicNode),
+                RpcProvider::Sepolia => Some(EthSepoliaService::Sepolia),
+            }
+            .map(RpcService::EthSepolia),
+            EthereumNetwork::ArbitrumOne => {
+                l2_mainnet_service(provider).map(RpcService::ArbitrumOne)
+            }
+            EthereumNetwork::BaseMainnet => {
+                l2_mainnet_service(provider).map(RpcService::BaseMainnet)
+            }
+            EthereumNetwork::OptimismMainnet => {
+                l2_mainnet_service(provider).map(RpcService::OptimismMainnet)
+            }
+            // Custom networks are only reached through their own endpoints.
+            EthereumNetwork::Custom { .. } => None,
         }
     }
+
+    /// Group the given RPC services of this network for a call to the EVM RPC canister.
+    pub fn rpc_services(&self, services: Vec<RpcService>) -> RpcServices {
+        match self {
+            EthereumNetwork::Mainnet => RpcServices::EthMainnet(Some(
+                services
+                    .into_iter()
+                    .filter_map(|service| match service {
+                        RpcService::EthMainnet(service) => Some(service),
+                        _ => None,
+                    })
+                    .collect(),
+            )),
+            EthereumNetwork::Sepolia => RpcServices::EthSepolia(Some(
+                services
+                    .into_iter()
+                    .filter_map(|service| match service {
+                        RpcService::EthSepolia(service) => Some(service),
+                        _ => None,
+                    })
+                    .collect(),
+            )),
+            EthereumNetwork::ArbitrumOne => RpcServices::ArbitrumOne(Some(
+                services
+                    .into_iter()
+                    .filter_map(|service| match service {
+                        RpcService::ArbitrumOne(service) => Some(service),
+                        _ => None,
+                    })
+                    .collect(),
+            )),
+            EthereumNetwork::BaseMainnet => RpcServices::BaseMainnet(Some(
+                services
+                    .into_iter()
+                    .filter_map(|service| match service {
+                        RpcService::BaseMainnet(service) => Some(service),
+                        _ => None,
+                    })
+                    .collect(),
+            )),
+            EthereumNetwork::OptimismMainnet => RpcServices::OptimismMainnet(Some(
+                services
+                    .into_iter()
+                    .filter_map(|service| match service {
+                        RpcService::OptimismMainnet(service) => Some(service),
+                        _ => None,
+                    })
+                    .collect(),
+            )),
+            EthereumNetwork::Custom { chain_id, .. } => RpcServices::Custom {
+                chainId: *chain_id,
+                services: services
+                    .into_iter()
+                    .filter_map(|service| match service {
+                        RpcService::Custom(api) => Some(api),
+                        _ => None,
+                    })
+                    .collect(),
+            },
+        }
+    }
+}
+
+/// An EVM chain the canister operates on, with the RPC providers used to reach it.
+#[derive(CandidType, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct ChainConfig {
+    pub ethereum_network: EthereumNetwork,
+    /// RPC providers to query, in order of preference. Defaults to a selection for the network.
+    pub rpc_providers: Option<Vec<RpcProvider>>,
+    /// JSON-RPC endpoints to query instead of the providers known to the EVM RPC canister.
+    pub custom_rpc_services: Option<Vec<RpcApi>>,
+    /// How to reconcile different results from the providers. Defaults to `Equality`.
+    pub consensus_strategy: Option<ConsensusStrategy>,
+    /// How signed transactions are sent to the providers. Defaults to `RoundRobin`.
+    pub broadcast_strategy: Option<BroadcastStrategy>,
+}
+
+/// The block tag designating the blocks that are considered safe from reorgs.
+#[derive(CandidType, Deserialize, Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub enum FinalityTag {
+    /// Blocks finalized by the consensus of the chain.
+    #[default]
+    Finalized,
+    /// Blocks that are unlikely to be reorged, available earlier than finalized ones.
+    Safe,
+}
+
+impl FinalityTag {
+    pub fn block_tag(&self) -> BlockTag {
+        match self {
+            FinalityTag::Finalized => BlockTag::Finalized,
+            FinalityTag::Safe => BlockTag::Safe,
+        }
+    }
+}
+
+/// How a signed transaction is sent to the RPC providers of a chain.
+///
+/// Since the transaction is signed, a single provider accepting it is enough:
+/// the other providers only add availability.
+#[derive(CandidType, Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+pub enum BroadcastStrategy {
+    /// Send to one provider at a time, moving on to the next one on failure.
+    /// The first provider tried rotates between transactions.
+    #[default]
+    RoundRobin,
+    /// Send to all providers at once.
+    AllAtOnce,
+}
+
+/// How the results of several RPC providers are reduced to a single one.
+///
+/// The version of the EVM RPC canister interface used here does not support passing
+/// a consensus strategy in `RpcConfig`, so it is applied by the canister itself to
+/// the `Inconsistent` results returned by the EVM RPC canister.
+#[derive(CandidType, Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+pub enum ConsensusStrategy {
+    /// All providers must return the same result.
+    #[default]
+    Equality,
+    /// At least `min` of the providers queried must return the same result.
+    Threshold { min: u8 },
+}
+
+impl ConsensusStrategy {
+    /// Return the result that enough providers agree on, if any.
+    /// Failed calls are given as `None` and never count towards the threshold.
+    /// If several results reach the threshold, the one returned by the most providers
+    /// wins, and there is no consensus when they are tied.
+    pub fn reduce<T: PartialEq>(&self, results: Vec<Option<T>>) -> Option<T> {
+        let min = match self {
+            ConsensusStrategy::Equality => results.len(),
+            ConsensusStrategy::Threshold { min } => *min as usize,
+        };
+        let mut candidates: Vec<(T, usize)> = Vec::new();
+        for result in results.into_iter().flatten() {
+            match candidates.iter_mut().find(|(candidate, _)| candidate == &result) {
+                Some((_, count)) => *count += 1,
+                None => candidates.push((result, 1)),
+            }
+        }
+        let max_count = candidates.iter().map(|(_, count)| *count).max()?;
+        if max_count < min.max(1) {
+            return None;
+        }
+        let mut winners = candidates
+            .into_iter()
+            .filter(|(_, count)| *count == max_count);
+        match (winners.next(), winners.next()) {
+            (Some((result, _)), None) => Some(result),
+            _ => None,
+        }
+    }
+
+    /// Return the highest block number that enough providers have reached, so that they
+    /// can all be queried at that block. Failed calls are given as `None` and never count.
+    pub fn reduce_block_number(&self, results: Vec<Option<u64>>) -> Option<u64> {
+        let min = match self {
+            ConsensusStrategy::Equality => results.len(),
+            ConsensusStrategy::Threshold { min } => *min as usize,
+        };
+        let mut block_numbers: Vec<u64> = results.into_iter().flatten().collect();
+        block_numbers.sort_unstable_by(|a, b| b.cmp(a));
+        block_numbers.get(min.max(1) - 1).copied()
+    }
+}
+
+impl ChainConfig {
+    pub fn chain_id(&self) -> u64 {
+        self.ethereum_network.chain_id()
+    }
+
+    pub fn consensus_strategy(&self) -> ConsensusStrategy {
+        self.consensus_strategy.clone().unwrap_or_default()
+    }
+
+    pub fn broadcast_strategy(&self) -> BroadcastStrategy {
+        self.broadcast_strategy.clone().unwrap_or_default()
+    }
+
+    /// Return the network as reached through the custom RPC services, if any are configured.
+    fn effective_network(&self) -> EthereumNetwork {
+        match &self.custom_rpc_services {
+            Some(rpc_services) => EthereumNetwork::Custom {
+                chain_id: self.chain_id(),
+                rpc_services: rpc_services.clone(),
+            },
+            None => self.ethereum_network.clone(),
+        }
+    }
+
+    /// Return the configured RPC providers, or a default selection for the network.
+    fn rpc_providers(&self) -> Vec<RpcProvider> {
+        match &self.rpc_providers {
+            Some(providers) => providers.clone(),
+            None => match self.ethereum_network {
+                EthereumNetwork::Mainnet => {
+                    vec![RpcProvider::PublicNode, RpcProvider::Ankr, RpcProvider::Cloudflare]
+                }
+                EthereumNetwork::Sepolia
+                | EthereumNetwork::ArbitrumOne
+                | EthereumNetwork::BaseMainnet
+                | EthereumNetwork::OptimismMainnet => {
+                    vec![RpcProvider::PublicNode, RpcProvider::Ankr, RpcProvider::BlockPi]
+                }
+                EthereumNetwork::Custom { .. } => vec![],
+            },
+        }
+    }
+
+    /// Return the RPC services queried for the network.
+    /// They are always given explicitly, so that the results are reduced with the consensus
+    /// strategy over the same services as the ones it was validated against.
+    pub fn evm_rpc_services(&self) -> RpcServices {
+        self.effective_network().rpc_services(self.json_rpc_services())
+    }
+
+    /// Return the given subset of the RPC services of the network.
+    pub fn evm_rpc_services_of(&self, services: Vec<RpcService>) -> RpcServices {
+        self.effective_network().rpc_services(services)
+    }
+
+    /// Return the single RPC service (the preferred provider) used for raw JSON-RPC requests.
+    pub fn json_rpc_service(&self) -> RpcService {
+        self.json_rpc_services()
+            .into_iter()
+            .next()
+            .expect("BUG: no RPC provider configured")
+    }
+
+    /// Return the RPC services queried when raw JSON-RPC requests need consensus.
+    pub fn json_rpc_services(&self) -> Vec<RpcService> {
+        match &self.effective_network() {
+            EthereumNetwork::Custom { rpc_services, .. } => rpc_services
+                .iter()
+                .cloned()
+                .map(RpcService::Custom)
+                .collect(),
+            ethereum_network => self
+                .rpc_providers()
+                .into_iter()
+                .filter_map(|provider| ethereum_network.rpc_service(provider))
+                .collect(),
+        }
+    }
+}
+
+/// Providers shared by the L2 networks supported by the EVM RPC canister.
+fn l2_mainnet_service(provider: RpcProvider) -> Option<L2MainnetService> {
+    match provider {
+        RpcProvider::Alchemy => Some(L2MainnetService::Alchemy),
+        RpcProvider::Ankr => Some(L2MainnetService::Ankr),
+        RpcProvider::BlockPi => Some(L2MainnetService::BlockPi),
+        RpcProvider::PublicNode => Some(L2MainnetService::PublicNode),
+        RpcProvider::Cloudflare | RpcProvider::Sepolia => None,
+    }
 }
 
 #[derive(CandidType, Deserialize, Debug, Default, PartialEq, Eq, Clone)]
@@ -247,30 +1522,171 @@ impl From<&EcdsaKeyName> for EcdsaKeyId {
 }
 
 
-pub fn validate_caller_not_anonymous() -> Principal {
+fn parse_recipient(address: &str) -> Result<Address, WalletError> {
+    Address::from_str(address).map_err(|e| WalletError::InvalidRecipient {
+        address: address.to_string(),
+        reason: e.to_string(),
+    })
+}
+
+
+fn parse_address(address: &str) -> Result<Address, WalletError> {
+    Address::from_str(address).map_err(|e| WalletError::InvalidAddress {
+        address: address.to_string(),
+        reason: e.to_string(),
+    })
+}
+
+
+fn parse_calldata(data: &str) -> Result<Vec<u8>, WalletError> {
+    hex::decode(data).map_err(|e| {
+        WalletError::InvalidArgument(format!("failed to parse the calldata {}: {}", data, e))
+    })
+}
+
+
+pub fn validate_caller_not_anonymous() -> Result<Principal, WalletError> {
     let principal = ic_cdk::caller();
     if principal == Principal::anonymous() {
-        panic!("anonymous principal is not allowed");
+        return Err(WalletError::AnonymousCaller);
     }
-    principal
+    Ok(principal)
 }
 
 
-fn nat_to_u64(nat: Nat) -> u64 {
+fn nat_to_u64(nat: Nat) -> Result<u64, WalletError> {
     use num_traits::cast::ToPrimitive;
     nat.0
         .to_u64()
-        .unwrap_or_else(|| ic_cdk::trap(&format!("Nat {} doesn't fit into a u64", nat)))
+        .ok_or_else(|| WalletError::InvalidArgument(format!("Nat {} doesn't fit into a u64", nat)))
 }
 
-fn nat_to_u256(value: Nat) -> U256 {
+fn nat_to_u128(nat: Nat) -> Result<u128, WalletError> {
+    use num_traits::cast::ToPrimitive;
+    nat.0
+        .to_u128()
+        .ok_or_else(|| WalletError::InvalidArgument(format!("Nat {} doesn't fit into a u128", nat)))
+}
+
+fn nat_to_u256(value: Nat) -> Result<U256, WalletError> {
     let value_bytes = value.0.to_bytes_be();
-    assert!(
-        value_bytes.len() <= 32,
-        "Nat does not fit in a U256: {}",
-        value
-    );
+    if value_bytes.len() > 32 {
+        return Err(WalletError::InvalidArgument(format!(
+            "Nat does not fit in a U256: {}",
+            value
+        )));
+    }
     let mut value_u256 = [0u8; 32];
     value_u256[32 - value_bytes.len()..].copy_from_slice(&value_bytes);
-    U256::from_be_bytes(value_u256)
-}
\ No newline at end of file
+    Ok(U256::from_be_bytes(value_u256))
+}
+
+// Generate the Candid interface of the canister from the annotated methods.
+ic_cdk::export_candid!();
+
+#[cfg(test)]
+mod tests {
+    use super::ConsensusStrategy;
+    use candid_parser::utils::{service_compatible, service_equal, CandidSource};
+    use std::path::PathBuf;
+
+    fn checked_in_did_file() -> PathBuf {
+        PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("icp-ethereum-wallet-backend.did")
+    }
+
+    #[test]
+    fn should_be_backward_compatible_with_checked_in_candid_interface() {
+        let generated_interface = super::__export_service();
+        service_compatible(
+            CandidSource::Text(&generated_interface),
+            CandidSource::File(checked_in_did_file().as_path()),
+        )
+        .unwrap_or_else(|e| {
+            panic!(
+                "the canister interface is not backward compatible with the checked-in .did file: {:?}",
+                e
+            )
+        });
+    }
+
+    #[test]
+    fn should_match_checked_in_candid_interface() {
+        let generated_interface = super::__export_service();
+        service_equal(
+            CandidSource::Text(&generated_interface),
+            CandidSource::File(checked_in_did_file().as_path()),
+        )
+        .unwrap_or_else(|e| {
+            panic!(
+                "the checked-in .did file diverges from the canister interface, \
+                update it with the generated interface:\n{}\nerror: {:?}",
+                generated_interface, e
+            )
+        });
+    }
+
+    #[test]
+    fn should_reduce_with_equality() {
+        let equality = ConsensusStrategy::Equality;
+        assert_eq!(equality.reduce(vec![Some(1), Some(1), Some(1)]), Some(1));
+        assert_eq!(equality.reduce(vec![Some(1), Some(2), Some(1)]), None);
+        assert_eq!(equality.reduce(Vec::<Option<u8>>::new()), None);
+    }
+
+    #[test]
+    fn should_not_reduce_with_equality_when_a_provider_failed() {
+        let equality = ConsensusStrategy::Equality;
+        assert_eq!(equality.reduce(vec![Some(1), None, Some(1)]), None);
+        assert_eq!(equality.reduce(vec![None::<u8>, None]), None);
+    }
+
+    #[test]
+    fn should_reduce_with_threshold() {
+        let threshold = ConsensusStrategy::Threshold { min: 2 };
+        assert_eq!(threshold.reduce(vec![Some(1), Some(2), Some(1)]), Some(1));
+        assert_eq!(threshold.reduce(vec![Some(1), None, Some(1)]), Some(1));
+        assert_eq!(threshold.reduce(vec![Some(1), None, Some(2)]), None);
+        assert_eq!(threshold.reduce(vec![None::<u8>, None, None]), None);
+    }
+
+    #[test]
+    fn should_not_reduce_with_threshold_on_tie() {
+        let threshold = ConsensusStrategy::Threshold { min: 2 };
+        assert_eq!(
+            threshold.reduce(vec![Some(1), Some(2), Some(1), Some(2)]),
+            None
+        );
+        let threshold = ConsensusStrategy::Threshold { min: 1 };
+        assert_eq!(threshold.reduce(vec![Some(1), Some(2)]), None);
+        assert_eq!(threshold.reduce(vec![Some(2), Some(1), Some(1)]), Some(1));
+    }
+
+    #[test]
+    fn should_reduce_block_number_to_the_block_reached_by_enough_providers() {
+        let equality = ConsensusStrategy::Equality;
+        assert_eq!(
+            equality.reduce_block_number(vec![Some(12), Some(10), Some(11)]),
+            Some(10)
+        );
+        assert_eq!(
+            equality.reduce_block_number(vec![Some(12), None, Some(11)]),
+            None
+        );
+        let threshold = ConsensusStrategy::Threshold { min: 2 };
+        assert_eq!(
+            threshold.reduce_block_number(vec![Some(12), Some(10), Some(11)]),
+            Some(11)
+        );
+        assert_eq!(
+            threshold.reduce_block_number(vec![Some(12), None, None]),
+            None
+        );
+    }
+
+    #[test]
+    fn should_never_reduce_with_zero_threshold_and_no_result() {
+        let threshold = ConsensusStrategy::Threshold { min: 0 };
+        assert_eq!(threshold.reduce(vec![None::<u8>]), None);
+        assert_eq!(threshold.reduce(vec![Some(1)]), Some(1));
+    }
+}
@@ -0,0 +1,294 @@
+use crate::state::mutate_state;
+use ic_ethereum_types::Address;
+use std::collections::BTreeSet;
+
+/// Nonces handed out by the canister for one address.
+///
+/// Update calls interleave at every `await`, so a nonce must be reserved in a single
+/// state mutation (without awaiting in between) to guarantee that two concurrent
+/// sends from the same address never get the same nonce.
+/// The tracker is not persisted across upgrades: it is reconciled with the
+/// on-chain transaction counts on each reservation.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct NonceTracker {
+    /// Next nonce that was never handed out.
+    next_nonce: u64,
+    /// Nonces reserved by sends that are still in progress.
+    in_flight: BTreeSet<u64>,
+    /// Nonces below `next_nonce` whose send failed and that must be reused first.
+    gaps: BTreeSet<u64>,
+}
+
+impl NonceTracker {
+    // -------------------------------------------------------------------------
+    // Reserve the next nonce given the transaction counts of the address at the
+    // latest block and including pending transactions, if the latter is known.
+    // `sent_nonces` are the nonces of transactions sent by the canister that are
+    // still pending: they are never reused, even if the pending count lags behind.
+    // -------------------------------------------------------------------------
+    pub fn reserve(
+        &mut self,
+        latest_count: u64,
+        pending_count: Option<u64>,
+        sent_nonces: &BTreeSet<u64>,
+    ) -> u64 {
+        let missing = self.dropped_nonces(pending_count, sent_nonces);
+        // Nonces below the latest count are mined and can no longer be reused.
+        self.gaps.retain(|nonce| *nonce >= latest_count);
+        // Transactions may have been sent without going through the tracker (e.g. explicit nonces),
+        // and the tracker may have been reset while tracked transactions are still pending.
+        let known_count = pending_count
+            .unwrap_or(latest_count)
+            .max(latest_count)
+            .max(sent_nonces.last().map_or(0, |nonce| nonce + 1));
+        if self.next_nonce < known_count {
+            self.next_nonce = known_count;
+            self.gaps.retain(|nonce| *nonce >= known_count);
+        }
+        self.gaps.extend(missing);
+        let nonce = match self.gaps.pop_first() {
+            Some(nonce) => nonce,
+            None => {
+                let nonce = self.next_nonce;
+                self.next_nonce += 1;
+                nonce
+            }
+        };
+        self.in_flight.insert(nonce);
+        nonce
+    }
+
+    // -------------------------------------------------------------------------
+    // Return the nonces that were handed out but are missing from the mempool.
+    // Without any send in progress, every nonce handed out should either be
+    // mined or pending. Missing ones were dropped and would block all later
+    // transactions, so they are reused. Gaps are only detected against a known
+    // pending count, since the latest count does not include the mempool.
+    // -------------------------------------------------------------------------
+    pub fn dropped_nonces(
+        &self,
+        pending_count: Option<u64>,
+        sent_nonces: &BTreeSet<u64>,
+    ) -> Vec<u64> {
+        match pending_count {
+            Some(pending_count) if self.in_flight.is_empty() => (pending_count..self.next_nonce)
+                .filter(|nonce| !self.gaps.contains(nonce) && !sent_nonces.contains(nonce))
+                .collect(),
+            _ => vec![],
+        }
+    }
+
+    /// Mark the transaction using the nonce as sent.
+    pub fn confirm(&mut self, nonce: u64) {
+        self.in_flight.remove(&nonce);
+    }
+
+    /// Give back a nonce whose transaction could not be sent.
+    pub fn release(&mut self, nonce: u64) {
+        if !self.in_flight.remove(&nonce) {
+            return;
+        }
+        if nonce + 1 == self.next_nonce {
+            self.next_nonce = nonce;
+            // Gaps at the top are not gaps anymore.
+            while self
+                .gaps
+                .last()
+                .is_some_and(|last| last + 1 == self.next_nonce)
+            {
+                self.next_nonce = self.gaps.pop_last().expect("BUG: gap was checked");
+            }
+        } else {
+            self.gaps.insert(nonce);
+        }
+    }
+}
+
+/// A nonce reserved for a transaction being sent.
+/// Dropping the reservation without confirming it gives the nonce back,
+/// which also happens when the call traps after an `await`.
+#[must_use]
+#[derive(Debug)]
+pub struct NonceReservation {
+    chain_id: u64,
+    address: Address,
+    nonce: u64,
+    confirmed: bool,
+}
+
+impl NonceReservation {
+    /// Reserve a nonce for the address on the chain, see [`NonceTracker::reserve`].
+    pub fn new(
+        chain_id: u64,
+        address: Address,
+        latest_count: u64,
+        pending_count: Option<u64>,
+    ) -> Self {
+        let nonce = mutate_state(|s| {
+            let sent_nonces = s.pending_nonces(chain_id, &address);
+            let tracker = s.nonce_tracker_mut(chain_id, address);
+            let missing = tracker.dropped_nonces(pending_count, &sent_nonces);
+            if !missing.is_empty() {
+                ic_cdk::println!(
+                    "Detected nonce gap of {} on chain {}: pending transaction count is {:?}. Reusing nonces {:?}",
+                    address,
+                    chain_id,
+                    pending_count,
+                    missing
+                );
+            }
+            tracker.reserve(latest_count, pending_count, &sent_nonces)
+        });
+        Self {
+            chain_id,
+            address,
+            nonce,
+            confirmed: false,
+        }
+    }
+
+    pub fn nonce(&self) -> u64 {
+        self.nonce
+    }
+
+    /// Keep the nonce reserved since its transaction was sent.
+    pub fn confirm(mut self) {
+        mutate_state(|s| {
+            s.nonce_tracker_mut(self.chain_id, self.address)
+                .confirm(self.nonce)
+        });
+        self.confirmed = true;
+    }
+}
+
+impl Drop for NonceReservation {
+    fn drop(&mut self) {
+        if !self.confirmed {
+            mutate_state(|s| {
+                s.nonce_tracker_mut(self.chain_id, self.address)
+                    .release(self.nonce)
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn confirmed_tracker(count: u64) -> NonceTracker {
+        let mut tracker = NonceTracker::default();
+        for expected in 0..count {
+            let nonce = tracker.reserve(0, Some(expected), &BTreeSet::new());
+            assert_eq!(nonce, expected);
+            tracker.confirm(nonce);
+        }
+        tracker
+    }
+
+    #[test]
+    fn should_start_from_pending_count() {
+        let mut tracker = NonceTracker::default();
+        assert_eq!(tracker.reserve(3, Some(5), &BTreeSet::new()), 5);
+        assert_eq!(tracker.reserve(3, Some(5), &BTreeSet::new()), 6);
+    }
+
+    #[test]
+    fn should_start_from_latest_count_without_pending_count() {
+        let mut tracker = NonceTracker::default();
+        assert_eq!(tracker.reserve(3, None, &BTreeSet::new()), 3);
+    }
+
+    #[test]
+    fn should_skip_tracked_nonces_after_reset_without_pending_count() {
+        let mut tracker = NonceTracker::default();
+        let sent_nonces = BTreeSet::from([5, 6]);
+        assert_eq!(tracker.reserve(5, None, &sent_nonces), 7);
+        assert_eq!(tracker.reserve(5, None, &sent_nonces), 8);
+    }
+
+    #[test]
+    fn should_reuse_released_nonce_in_the_middle() {
+        let mut tracker = NonceTracker::default();
+        for expected in 0..3 {
+            assert_eq!(tracker.reserve(0, Some(0), &BTreeSet::new()), expected);
+        }
+        tracker.release(1);
+        assert_eq!(tracker.reserve(0, Some(0), &BTreeSet::new()), 1);
+        assert_eq!(tracker.reserve(0, Some(0), &BTreeSet::new()), 3);
+    }
+
+    #[test]
+    fn should_lower_next_nonce_when_releasing_top_of_range() {
+        let mut tracker = NonceTracker::default();
+        for expected in 0..3 {
+            assert_eq!(tracker.reserve(0, Some(0), &BTreeSet::new()), expected);
+        }
+        tracker.release(1);
+        tracker.release(2);
+        assert_eq!(tracker.next_nonce, 1);
+        assert!(tracker.gaps.is_empty());
+        assert_eq!(tracker.reserve(0, Some(0), &BTreeSet::new()), 1);
+        assert_eq!(tracker.reserve(0, Some(0), &BTreeSet::new()), 2);
+    }
+
+    #[test]
+    fn should_ignore_release_of_confirmed_nonce() {
+        let mut tracker = confirmed_tracker(2);
+        tracker.release(1);
+        assert_eq!(tracker.reserve(0, Some(2), &BTreeSet::new()), 2);
+    }
+
+    #[test]
+    fn should_reuse_nonces_dropped_from_mempool() {
+        let mut tracker = confirmed_tracker(3);
+        assert_eq!(
+            tracker.dropped_nonces(Some(1), &BTreeSet::new()),
+            vec![1, 2]
+        );
+        assert_eq!(tracker.reserve(1, Some(1), &BTreeSet::new()), 1);
+        assert_eq!(tracker.reserve(1, Some(1), &BTreeSet::new()), 2);
+        assert_eq!(tracker.reserve(1, Some(1), &BTreeSet::new()), 3);
+    }
+
+    #[test]
+    fn should_not_reuse_nonces_of_pending_transactions_when_pending_count_lags() {
+        let mut tracker = confirmed_tracker(3);
+        let sent_nonces = BTreeSet::from([1, 2]);
+        assert_eq!(
+            tracker.dropped_nonces(Some(1), &sent_nonces),
+            Vec::<u64>::new()
+        );
+        assert_eq!(tracker.reserve(1, Some(1), &sent_nonces), 3);
+    }
+
+    #[test]
+    fn should_not_detect_gaps_without_pending_count() {
+        let mut tracker = confirmed_tracker(3);
+        assert_eq!(
+            tracker.dropped_nonces(None, &BTreeSet::new()),
+            Vec::<u64>::new()
+        );
+        assert_eq!(tracker.reserve(0, None, &BTreeSet::new()), 3);
+    }
+
+    #[test]
+    fn should_not_detect_gaps_while_sends_are_in_flight() {
+        let mut tracker = confirmed_tracker(2);
+        assert_eq!(tracker.reserve(0, Some(2), &BTreeSet::new()), 2);
+        assert_eq!(tracker.reserve(0, Some(0), &BTreeSet::new()), 3);
+    }
+
+    #[test]
+    fn should_forget_gaps_below_latest_count() {
+        let mut tracker = NonceTracker::default();
+        for expected in 0..3 {
+            assert_eq!(tracker.reserve(0, Some(0), &BTreeSet::new()), expected);
+        }
+        tracker.release(0);
+        tracker.confirm(1);
+        tracker.confirm(2);
+        // Nonce 0 was used by a transaction sent with an explicit nonce.
+        assert_eq!(tracker.reserve(3, Some(3), &BTreeSet::new()), 3);
+    }
+}
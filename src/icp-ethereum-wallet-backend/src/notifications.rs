@@ -0,0 +1,164 @@
+use crate::deposits::{Deposit, DepositStatus};
+use crate::guard::DeliveryGuard;
+use crate::state::{mutate_state, read_state};
+use candid::{CandidType, Deserialize, Nat, Principal};
+use std::time::Duration;
+
+/// How often queued notifications are delivered.
+pub const NOTIFICATION_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Delay before the first retry of a failed delivery, doubled after each failure.
+const RETRY_BASE_DELAY: Duration = Duration::from_secs(30);
+
+/// Maximum delay between two delivery attempts of a notification.
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(60 * 60);
+
+/// Number of failed deliveries after which a notification is dropped, about a day
+/// of retries. Subscribers can catch up on missed deposits with `get_deposits`.
+const MAX_NOTIFICATION_ATTEMPTS: u32 = 30;
+
+/// Maximum number of notifications waiting to be delivered. The oldest ones are
+/// dropped first, so that unresponsive subscribers cannot grow the state forever.
+const MAX_QUEUED_NOTIFICATIONS: usize = 10_000;
+
+/// Maximum number of notifications awaiting the reply of a single subscriber.
+/// The other due notifications of the subscriber wait for a later run.
+const MAX_DELIVERIES_PER_SUBSCRIBER: usize = 10;
+
+/// Argument of the callback method of subscribed canisters, sent when a deposit is credited
+/// with the `Confirmed` status, and again once its block is `Finalized` or `Reverted`.
+/// A subscriber that acts on confirmed deposits must undo their effect if they are reverted.
+///
+/// A notification can be delivered more than once, e.g. if the canister is upgraded while
+/// waiting for the reply of the subscriber. Subscribers should ignore notifications with an
+/// `owner`, `deposit_index` and `status` they already processed.
+#[derive(CandidType, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct DepositNotification {
+    pub owner: Principal,
+    /// Position of the deposit in the owner's deposits, see `get_deposits`.
+    pub deposit_index: u64,
+    pub chain_id: u64,
+    /// Address of the ERC-20 token contract, or `None` for ETH.
+    pub token: Option<String>,
+    pub amount: Nat,
+    pub tx_hash: Option<String>,
+    pub block_number: u64,
+    pub block_hash: String,
+    pub status: DepositStatus,
+}
+
+/// A notification waiting to be delivered to a subscriber.
+#[derive(CandidType, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct QueuedNotification {
+    pub subscriber: Principal,
+    pub notification: DepositNotification,
+    /// Number of failed deliveries so far.
+    pub attempts: u32,
+    /// Time before which the notification is not delivered again, in nanoseconds since the epoch.
+    pub next_attempt_at: u64,
+}
+
+/// Queue a notification of the deposit and its current status for each subscribed canister.
+pub fn notify_deposit(owner: Principal, deposit_index: u64, deposit: &Deposit) {
+    let notification = DepositNotification {
+        owner,
+        deposit_index,
+        chain_id: deposit.chain_id,
+        token: deposit.token.clone(),
+        amount: deposit.amount.clone(),
+        tx_hash: deposit.tx_hash.clone(),
+        block_number: deposit.block_number,
+        block_hash: deposit.block_hash.clone(),
+        status: deposit.status,
+    };
+    let dropped = mutate_state(|s| {
+        let mut dropped = vec![];
+        for subscriber in s.subscribers() {
+            dropped.extend(s.queue_notification(
+                QueuedNotification {
+                    subscriber,
+                    notification: notification.clone(),
+                    attempts: 0,
+                    next_attempt_at: 0,
+                },
+                MAX_QUEUED_NOTIFICATIONS,
+            ));
+        }
+        dropped
+    });
+    for queued in dropped {
+        ic_cdk::println!(
+            "Notification queue is full, dropped notification of {} for {:?}",
+            queued.subscriber,
+            queued.notification
+        );
+    }
+}
+
+// -----------------------------------------------------------------------------
+// Call the callback method of the subscribers with each queued notification.
+// A notification is removed from the queue once the subscriber replied
+// successfully, and is otherwise retried with an exponential backoff for as long
+// as the subscription exists, up to `MAX_NOTIFICATION_ATTEMPTS` times.
+// Each delivery runs on its own, so that a subscriber that does not reply does not
+// delay the others: the notification is leased until the maximum retry delay, after
+// which it is delivered again if no reply arrived. At most
+// `MAX_DELIVERIES_PER_SUBSCRIBER` deliveries to a subscriber are in flight.
+// -----------------------------------------------------------------------------
+pub fn deliver_notifications() {
+    let now = ic_cdk::api::time();
+    for (id, queued) in read_state(|s| s.due_notifications(now)) {
+        let method = match read_state(|s| s.subscription(&queued.subscriber)) {
+            Some(method) => method,
+            None => {
+                mutate_state(|s| s.remove_notification(id));
+                continue;
+            }
+        };
+        let guard = match DeliveryGuard::new(queued.subscriber, MAX_DELIVERIES_PER_SUBSCRIBER) {
+            Some(guard) => guard,
+            None => continue,
+        };
+        let lease_end = now.saturating_add(MAX_RETRY_DELAY.as_nanos() as u64);
+        mutate_state(|s| s.reschedule_notification(id, queued.attempts, lease_end));
+        ic_cdk::spawn(deliver_notification(id, queued, method, guard));
+    }
+}
+
+async fn deliver_notification(
+    id: u64,
+    queued: QueuedNotification,
+    method: String,
+    _guard: DeliveryGuard,
+) {
+    let result: Result<(), _> =
+        ic_cdk::call(queued.subscriber, &method, (queued.notification.clone(),)).await;
+    match result {
+        Ok(()) => mutate_state(|s| s.remove_notification(id)),
+        Err((code, message)) => {
+            let attempts = queued.attempts.saturating_add(1);
+            let delay = RETRY_BASE_DELAY
+                .saturating_mul(2_u32.saturating_pow(attempts - 1))
+                .min(MAX_RETRY_DELAY);
+            ic_cdk::println!(
+                "Failed to notify {} of deposit {:?} (attempt {}): {:?} {}",
+                queued.subscriber,
+                queued.notification,
+                attempts,
+                code,
+                message
+            );
+            if attempts >= MAX_NOTIFICATION_ATTEMPTS {
+                ic_cdk::println!(
+                    "Giving up notifying {} of deposit {:?}",
+                    queued.subscriber,
+                    queued.notification
+                );
+                mutate_state(|s| s.remove_notification(id));
+                return;
+            }
+            let next_attempt_at = ic_cdk::api::time().saturating_add(delay.as_nanos() as u64);
+            mutate_state(|s| s.reschedule_notification(id, attempts, next_attempt_at));
+        }
+    }
+}
@@ -0,0 +1,278 @@
+use crate::broadcast::broadcast_raw_transaction;
+use crate::error::WalletError;
+use crate::guard::{TimerGuard, TimerTask};
+use crate::history;
+use crate::state::{mutate_state, read_state};
+use crate::{get_block, get_transaction_count, nat_to_u64, ChainConfig, FinalityTag, EVM_RPC};
+use candid::{CandidType, Deserialize, Nat, Principal};
+use evm_rpc_canister_types::{
+    GetTransactionReceiptResult, MultiGetTransactionReceiptResult, TransactionReceipt,
+};
+use ic_ethereum_types::Address;
+use std::collections::BTreeMap;
+use std::str::FromStr;
+use std::time::Duration;
+
+/// How often the receipts of pending transactions are fetched.
+pub const RECEIPT_POLLING_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Status of a transaction sent by the canister.
+#[derive(CandidType, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub enum TransactionStatus {
+    /// Sent but not yet included in a block.
+    Pending,
+    /// Included in a block and executed successfully.
+    /// The block hash is unknown for transactions mined before it was recorded.
+    Mined {
+        block_number: Nat,
+        gas_used: Nat,
+        block_hash: Option<String>,
+    },
+    /// Included in a block but the execution failed.
+    Reverted {
+        block_number: Nat,
+        gas_used: Nat,
+        block_hash: Option<String>,
+    },
+    /// Another transaction with the same nonce was mined instead.
+    Dropped,
+    /// Signed but rejected by the RPC providers when sent, so it was never broadcast.
+    Rejected { reason: String },
+}
+
+/// A transaction sent by the canister whose status is tracked.
+#[derive(CandidType, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct SentTransaction {
+    pub owner: Principal,
+    pub chain_id: u64,
+    /// Ethereum address of the owner's wallet.
+    pub from: String,
+    pub nonce: u64,
+    /// Time at which the transaction was sent, in nanoseconds since the epoch.
+    pub sent_at: u64,
+    pub status: TransactionStatus,
+    /// Position of the transaction in the owner's history.
+    pub history_index: u64,
+    /// Number of automatic fee bumps that led to this transaction.
+    /// Replacements requested by the owner do not count.
+    pub fee_bumps: u8,
+    /// Max fee per gas of the first transaction sent with this nonce, which bounds
+    /// the fees of automatic fee bumps. Unknown for transactions sent before it was tracked.
+    pub initial_max_fee_per_gas: Option<Nat>,
+    /// Hash of the transaction that replaced this one with the same nonce.
+    pub replaced_by: Option<String>,
+    /// Latest block when the transaction was first seen pending by the fee bumper.
+    pub pending_since_block: Option<u64>,
+    /// Finalized (or safe, see `FinalityTag`) block at which the block of the transaction
+    /// was found to be canonical. Until then, the receipt is fetched again in case of a reorg.
+    pub finalized_at_block: Option<u64>,
+}
+
+// -----------------------------------------------------------------------------
+// Fetch the receipts of all pending transactions and update their status.
+// A transaction without receipt is considered dropped once the transaction
+// count of its sender at the finalized block went past its nonce.
+// The receipts of mined transactions are fetched again until their block is
+// finalized: if the transaction was reorged into another block or back into
+// the mempool, its status is updated accordingly.
+// Transactions that are final are then removed from the state, the history
+// keeping their record.
+// Pending transactions that were not replaced are sent again, since their
+// broadcast may have failed or they may have been evicted from the mempool.
+// Nodes that already have them ignore the duplicate.
+// -----------------------------------------------------------------------------
+pub async fn poll_transaction_receipts() {
+    let _guard = match TimerGuard::new(TimerTask::PollReceipts) {
+        Some(guard) => guard,
+        None => return,
+    };
+    let transactions = read_state(|s| s.unfinalized_transactions());
+    let finality_tag = read_state(|s| s.finality_tag());
+    // Finalized block of each chain, fetched once per run.
+    let mut finalized_blocks: BTreeMap<u64, Option<u64>> = BTreeMap::new();
+    for (tx_hash, transaction) in transactions {
+        let status = match fetch_status(&tx_hash, &transaction, finality_tag).await {
+            Ok(status) => status,
+            Err(e) => {
+                ic_cdk::println!("Failed to update the status of {}: {:?}", tx_hash, e);
+                continue;
+            }
+        };
+        if status != transaction.status {
+            if transaction.status == TransactionStatus::Pending {
+                ic_cdk::println!("Transaction {} is now {:?}", tx_hash, status);
+            } else {
+                ic_cdk::println!(
+                    "Transaction {} was reorged: {:?} is now {:?}",
+                    tx_hash,
+                    transaction.status,
+                    status
+                );
+            }
+            mutate_state(|s| s.update_transaction_status(&tx_hash, status.clone()));
+            history::update_transaction_status(
+                transaction.owner,
+                transaction.history_index,
+                status.clone(),
+            );
+        }
+        let block_number = match status {
+            TransactionStatus::Mined { block_number, .. }
+            | TransactionStatus::Reverted { block_number, .. } => block_number,
+            TransactionStatus::Pending => {
+                if transaction.replaced_by.is_none() {
+                    rebroadcast(&tx_hash, &transaction).await;
+                }
+                continue;
+            }
+            TransactionStatus::Dropped | TransactionStatus::Rejected { .. } => continue,
+        };
+        let finalized_block = match finalized_blocks.get(&transaction.chain_id) {
+            Some(block_number) => *block_number,
+            None => {
+                let finalized_block =
+                    match finalized_block_number(transaction.chain_id, finality_tag).await {
+                        Ok(block_number) => Some(block_number),
+                        Err(e) => {
+                            ic_cdk::println!(
+                                "Failed to get the {:?} block of chain {}: {:?}",
+                                finality_tag,
+                                transaction.chain_id,
+                                e
+                            );
+                            None
+                        }
+                    };
+                finalized_blocks.insert(transaction.chain_id, finalized_block);
+                finalized_block
+            }
+        };
+        if let Some(finalized_block) = finalized_block {
+            if block_number <= Nat::from(finalized_block) {
+                mutate_state(|s| s.set_transaction_finalized(&tx_hash, finalized_block));
+            }
+        }
+    }
+    for (tx_hash, transaction) in mutate_state(|s| s.take_final_transactions()) {
+        history::index_final_transaction(tx_hash, transaction.owner, transaction.history_index);
+    }
+}
+
+async fn rebroadcast(tx_hash: &str, transaction: &SentTransaction) {
+    let raw_transaction =
+        match history::get_transaction(transaction.owner, transaction.history_index) {
+            Some(record) => record.raw_transaction,
+            None => return,
+        };
+    let result = match read_state(|s| s.chain(Some(transaction.chain_id))) {
+        Ok(chain) => broadcast_raw_transaction(&chain, &raw_transaction).await,
+        Err(e) => Err(e),
+    };
+    if let Err(e) = result {
+        ic_cdk::println!(
+            "Failed to send pending transaction {} again: {:?}",
+            tx_hash,
+            e
+        );
+    }
+}
+
+async fn finalized_block_number(
+    chain_id: u64,
+    finality_tag: FinalityTag,
+) -> Result<u64, WalletError> {
+    let chain = read_state(|s| s.chain(Some(chain_id)))?;
+    let (block_number, _) = get_block(&chain, finality_tag.block_tag()).await?;
+    nat_to_u64(block_number)
+}
+
+async fn fetch_status(
+    tx_hash: &str,
+    transaction: &SentTransaction,
+    finality_tag: FinalityTag,
+) -> Result<TransactionStatus, WalletError> {
+    let chain = read_state(|s| s.chain(Some(transaction.chain_id)))?;
+    if let Some(status) = get_receipt_status(&chain, tx_hash).await? {
+        return Ok(status);
+    }
+    let from = Address::from_str(&transaction.from).map_err(|e| {
+        WalletError::InvalidAddress {
+            address: transaction.from.clone(),
+            reason: e.to_string(),
+        }
+    })?;
+    // Another transaction with the same nonce could still be reorged out before its block is final.
+    let finalized_count =
+        nat_to_u64(get_transaction_count(&chain, &from, finality_tag.block_tag()).await?)?;
+    if finalized_count > transaction.nonce {
+        // Check again in case the transaction was mined in the meantime.
+        return Ok(get_receipt_status(&chain, tx_hash)
+            .await?
+            .unwrap_or(TransactionStatus::Dropped));
+    }
+    Ok(TransactionStatus::Pending)
+}
+
+fn status_from_receipt(receipt: &TransactionReceipt) -> TransactionStatus {
+    // The status of a receipt is 1 on success and 0 on failure (EIP-658).
+    if receipt.status == 1_u8 {
+        TransactionStatus::Mined {
+            block_number: receipt.blockNumber.clone(),
+            gas_used: receipt.gasUsed.clone(),
+            block_hash: Some(receipt.blockHash.clone()),
+        }
+    } else {
+        TransactionStatus::Reverted {
+            block_number: receipt.blockNumber.clone(),
+            gas_used: receipt.gasUsed.clone(),
+            block_hash: Some(receipt.blockHash.clone()),
+        }
+    }
+}
+
+// Fetch the receipt of the transaction and return the resulting status,
+// or `None` if the transaction was not mined yet.
+async fn get_receipt_status(
+    chain: &ChainConfig,
+    tx_hash: &str,
+) -> Result<Option<TransactionStatus>, WalletError> {
+    let rpc_services = chain.evm_rpc_services();
+    let evm_rpc_cycles = read_state(|s| s.evm_rpc_cycles());
+    let (result,) = EVM_RPC
+        .eth_get_transaction_receipt(rpc_services, None, tx_hash.to_string(), evm_rpc_cycles)
+        .await
+        .map_err(|e| {
+            WalletError::RpcError(format!(
+                "failed to get transaction receipt for {}, error: {:?}",
+                tx_hash, e
+            ))
+        })?;
+    match result {
+        MultiGetTransactionReceiptResult::Consistent(consistent_result) => match consistent_result {
+            GetTransactionReceiptResult::Ok(receipt) => Ok(receipt.as_ref().map(status_from_receipt)),
+            GetTransactionReceiptResult::Err(error) => Err(WalletError::RpcError(format!(
+                "failed to get transaction receipt for {}, error: {:?}",
+                tx_hash, error
+            ))),
+        },
+        MultiGetTransactionReceiptResult::Inconsistent(inconsistent_results) => chain
+            .consensus_strategy()
+            .reduce(
+                inconsistent_results
+                    .iter()
+                    .map(|(_, result)| match result {
+                        GetTransactionReceiptResult::Ok(receipt) => {
+                            Some(receipt.as_ref().map(status_from_receipt))
+                        }
+                        GetTransactionReceiptResult::Err(_) => None,
+                    })
+                    .collect(),
+            )
+            .ok_or_else(|| {
+                WalletError::RpcInconsistent(format!(
+                    "inconsistent results when retrieving transaction receipt for {}. Received results: {:?}",
+                    tx_hash, inconsistent_results
+                ))
+            }),
+    }
+}
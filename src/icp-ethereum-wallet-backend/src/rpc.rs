@@ -0,0 +1,219 @@
+use crate::error::WalletError;
+use crate::state::read_state;
+use crate::{nat_to_u64, ChainConfig, EVM_RPC};
+use candid::Nat;
+use evm_rpc_canister_types::{
+    BlockTag, GetBlockByNumberResult, MultiGetBlockByNumberResult, RequestResult, RpcService,
+};
+use num::{BigUint, Num};
+
+// -----------------------------------------------------------------------------
+// Send a raw JSON-RPC request through `EVM_RPC.request` and return the
+// `result` field of the response. Used for methods that the EVM RPC canister
+// does not expose as typed endpoints (e.g. `eth_estimateGas`, `eth_getCode`).
+// -----------------------------------------------------------------------------
+pub async fn json_rpc_request(
+    chain: &ChainConfig,
+    method: &str,
+    params: serde_json::Value,
+    max_response_size_bytes: u64,
+) -> Result<serde_json::Value, WalletError> {
+    json_rpc_request_to(chain.json_rpc_service(), method, params, max_response_size_bytes).await
+}
+
+// -----------------------------------------------------------------------------
+// Send the same raw JSON-RPC request to several providers and only return the
+// result if all of them agree, similarly to the consensus applied by the EVM
+// RPC canister on its typed endpoints (e.g. `eth_getTransactionCount`).
+// -----------------------------------------------------------------------------
+pub async fn multi_json_rpc_request(
+    chain: &ChainConfig,
+    method: &str,
+    params: serde_json::Value,
+    max_response_size_bytes: u64,
+) -> Result<serde_json::Value, WalletError> {
+    multi_json_rpc_request_with(chain, method, params, max_response_size_bytes, Ok).await
+}
+
+// -----------------------------------------------------------------------------
+// Same as `multi_json_rpc_request`, but the results are parsed before being
+// compared, so that providers returning the same data with different extra
+// fields (e.g. in log entries) are still considered to agree.
+// -----------------------------------------------------------------------------
+pub async fn multi_json_rpc_request_with<T, F>(
+    chain: &ChainConfig,
+    method: &str,
+    params: serde_json::Value,
+    max_response_size_bytes: u64,
+    parse: F,
+) -> Result<T, WalletError>
+where
+    T: Clone + PartialEq + std::fmt::Debug,
+    F: Fn(serde_json::Value) -> Result<T, WalletError>,
+{
+    let rpc_services = chain.json_rpc_services();
+    let mut results = Vec::with_capacity(rpc_services.len());
+    for rpc_service in rpc_services {
+        let result = json_rpc_request_to(
+            rpc_service.clone(),
+            method,
+            params.clone(),
+            max_response_size_bytes,
+        )
+        .await
+        .and_then(&parse);
+        results.push((rpc_service, result));
+    }
+    if results.is_empty() {
+        return Err(WalletError::RpcError("no RPC service configured".to_string()));
+    }
+    let (_, first) = &results[0];
+    if results.iter().all(|(_, result)| result == first) {
+        return first.clone();
+    }
+    chain
+        .consensus_strategy()
+        .reduce(results.iter().map(|(_, result)| result.clone().ok()).collect())
+        .ok_or_else(|| {
+            WalletError::RpcInconsistent(format!(
+                "inconsistent results when calling {} with {}. Received results: {:?}",
+                method, params, results
+            ))
+        })
+}
+
+async fn json_rpc_request_to(
+    rpc_service: RpcService,
+    method: &str,
+    params: serde_json::Value,
+    max_response_size_bytes: u64,
+) -> Result<serde_json::Value, WalletError> {
+    let json = serde_json::json!({
+        "jsonrpc": "2.0",
+        "method": method,
+        "params": params,
+        "id": 1,
+    })
+    .to_string();
+    let evm_rpc_cycles = read_state(|s| s.evm_rpc_cycles());
+    let (response,) = EVM_RPC
+        .request(
+            rpc_service,
+            json.clone(),
+            max_response_size_bytes,
+            evm_rpc_cycles,
+        )
+        .await
+        .map_err(|e| {
+            WalletError::RpcError(format!("failed to send request {}, error: {:?}", json, e))
+        })?;
+
+    match response {
+        RequestResult::Ok(body) => {
+            // A successful response has the format { "id": 1, "jsonrpc": "2.0", "result": ... },
+            // while a failed one carries an "error" object instead of the result.
+            let mut response: serde_json::Value = serde_json::from_str(&body).map_err(|e| {
+                WalletError::RpcError(format!("failed to parse response {}: {}", body, e))
+            })?;
+            if let Some(error) = response.get("error") {
+                return Err(json_rpc_error(&json, error));
+            }
+            response
+                .get_mut("result")
+                .map(serde_json::Value::take)
+                .ok_or_else(|| {
+                    WalletError::RpcError(format!("missing result in response {}", body))
+                })
+        }
+        RequestResult::Err(e) => Err(WalletError::RpcError(format!(
+            "received an error response for request {}: {:?}",
+            json, e
+        ))),
+    }
+}
+
+/// Return the number of the latest block of the chain that enough providers have reached,
+/// see [`crate::ConsensusStrategy::reduce_block_number`].
+pub async fn latest_block_number(chain: &ChainConfig) -> Result<u64, WalletError> {
+    let rpc_services = chain.evm_rpc_services();
+    let evm_rpc_cycles = read_state(|s| s.evm_rpc_cycles());
+    let (result,) = EVM_RPC
+        .eth_get_block_by_number(rpc_services, None, BlockTag::Latest, evm_rpc_cycles)
+        .await
+        .map_err(|e| {
+            WalletError::RpcError(format!("failed to get the latest block, error: {:?}", e))
+        })?;
+    let inconsistent_results = match result {
+        MultiGetBlockByNumberResult::Consistent(GetBlockByNumberResult::Ok(block)) => {
+            return nat_to_u64(block.number);
+        }
+        MultiGetBlockByNumberResult::Consistent(GetBlockByNumberResult::Err(error)) => {
+            return Err(WalletError::RpcError(format!(
+                "failed to get the latest block, error: {:?}",
+                error
+            )));
+        }
+        MultiGetBlockByNumberResult::Inconsistent(inconsistent_results) => inconsistent_results,
+    };
+    chain
+        .consensus_strategy()
+        .reduce_block_number(
+            inconsistent_results
+                .iter()
+                .map(|(_, result)| match result {
+                    GetBlockByNumberResult::Ok(block) => nat_to_u64(block.number.clone()).ok(),
+                    GetBlockByNumberResult::Err(_) => None,
+                })
+                .collect(),
+        )
+        .ok_or_else(|| {
+            WalletError::RpcInconsistent(format!(
+                "too few providers reached a common latest block. Received results: {:?}",
+                inconsistent_results
+            ))
+        })
+}
+
+/// Map the error object of a JSON-RPC response, recognizing the errors that callers can act upon.
+fn json_rpc_error(request: &str, error: &serde_json::Value) -> WalletError {
+    let message = error
+        .get("message")
+        .and_then(|m| m.as_str())
+        .unwrap_or_default()
+        .to_lowercase();
+    if message.contains("insufficient funds") {
+        WalletError::InsufficientFunds
+    } else if message.contains("nonce too low") {
+        WalletError::NonceTooLow
+    } else if message.contains("nonce too high") {
+        WalletError::NonceTooHigh
+    } else {
+        WalletError::RpcError(format!("request {} failed with error {}", request, error))
+    }
+}
+
+/// Parse a hex-encoded JSON-RPC quantity such as `"0x5208"`.
+pub fn parse_hex_quantity(value: &serde_json::Value) -> Result<Nat, WalletError> {
+    let hex = value
+        .as_str()
+        .and_then(|s| s.strip_prefix("0x"))
+        .ok_or_else(|| WalletError::RpcError(format!("expected a hex quantity, got {}", value)))?;
+    if hex.is_empty() {
+        return Ok(Nat::from(0_u8));
+    }
+    BigUint::from_str_radix(hex, 16)
+        .map(Nat)
+        .map_err(|e| WalletError::RpcError(format!("invalid hex quantity {}: {}", value, e)))
+}
+
+/// Convert a block tag into the block parameter of a JSON-RPC request.
+pub fn block_tag_param(block: &BlockTag) -> serde_json::Value {
+    match block {
+        BlockTag::Earliest => "earliest".into(),
+        BlockTag::Safe => "safe".into(),
+        BlockTag::Finalized => "finalized".into(),
+        BlockTag::Latest => "latest".into(),
+        BlockTag::Pending => "pending".into(),
+        BlockTag::Number(number) => format!("0x{}", number.0.to_str_radix(16)).into(),
+    }
+}
@@ -1,10 +1,41 @@
+use crate::deposits::{DepositAccount, MAX_DEPOSIT_ACCOUNTS_PER_CHAIN};
 use crate::ecdsa::EcdsaPublicKey;
-use crate::{EcdsaKeyName, EthereumNetwork, InitArg};
-use evm_rpc_canister_type::{EthMainnetService, EthSepoliaService, RpcServices};
-use ic_cdk::api::management_canister::ecdsa::EcdsaKeyId;
+use crate::error::WalletError;
+use crate::guard::TimerTask;
+use crate::nonce::NonceTracker;
+use crate::notifications::QueuedNotification;
+use crate::receipts::{SentTransaction, TransactionStatus};
+use crate::storage::{state_memory, VMem};
+use crate::{
+    BroadcastStrategy, ChainConfig, ConsensusStrategy, EcdsaKeyName, EthereumNetwork, FinalityTag,
+    InitArg, RpcProvider, UpgradeArg,
+};
+use evm_rpc_canister_types::RpcApi;
+use candid::{CandidType, Decode, Deserialize, Encode, Nat, Principal};
+use ic_cdk::api::management_canister::ecdsa::{EcdsaKeyId, EcdsaPublicKeyResponse};
+use ic_ethereum_types::Address;
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::{StableCell, Storable};
+use std::borrow::Cow;
 use std::cell::RefCell;
+use std::collections::{BTreeMap, BTreeSet};
 use std::ops::{Deref, DerefMut};
 
+/// Default upper bound on `max_fee_per_gas` (500 gwei) when none is given in the InitArg.
+pub const DEFAULT_MAX_FEE_PER_GAS_CEILING: u128 = 500_000_000_000;
+
+/// Default buffer added on top of the `eth_estimateGas` result when none is given in the InitArg.
+pub const DEFAULT_GAS_LIMIT_BUFFER_PERCENT: u8 = 20;
+
+/// Default number of automatic fee bumps of a single transaction.
+pub const DEFAULT_MAX_FEE_BUMPS: u8 = 3;
+
+/// Default number of blocks on top of the block of a deposit before it is credited.
+pub const DEFAULT_DEPOSIT_CONFIRMATIONS: u64 = 12;
+
+/// Default cycles attached to each call to the EVM RPC canister. Unused cycles are refunded.
+pub const DEFAULT_EVM_RPC_CYCLES: u128 = 2_000_000_000;
+
 thread_local! {
     /// Global state for the canister, stored in a thread-local RefCell for interior mutability.
     static STATE: RefCell<State> = RefCell::new(State::default());
@@ -12,15 +43,27 @@ thread_local! {
 
 /// Initialize the global state from the InitArg provided at canister init.
 pub fn init_state(init_args: InitArg) {
+    let default_chain = ChainConfig {
+        ethereum_network: init_args.ethereum_network.clone().unwrap_or_default(),
+        rpc_providers: init_args.rpc_providers.clone(),
+        custom_rpc_services: init_args.custom_rpc_services.clone(),
+        consensus_strategy: init_args.consensus_strategy.clone(),
+        broadcast_strategy: init_args.broadcast_strategy.clone(),
+    };
+    validate_chains(
+        &default_chain,
+        init_args.additional_chains.as_deref().unwrap_or_default(),
+    )
+    .unwrap_or_else(|e| ic_cdk::trap(&format!("invalid init argument: {}", e)));
     STATE.with(|s| *s.borrow_mut() = State::from(init_args));
 }
 /// Read-only access to the global state.
-pub fn read_state<R>(f: impl FnOnce(&State) -> R) -> R{
-    STATE.with(|s| f(s.borrow().deref()));
+pub fn read_state<R>(f: impl FnOnce(&State) -> R) -> R {
+    STATE.with(|s| f(s.borrow().deref()))
 }
 
 /// Mutable access to the global state.
-pub fn mutat_state<F, R>(f: F) -> R
+pub fn mutate_state<F, R>(f: F) -> R
 where
     F: FnOnce(&mut State) -> R,
 {
@@ -30,45 +73,820 @@ where
 /// Represents the canister state.
 #[derive(Debug, Default, PartialEq, Eq)]
 pub struct State {
-    /// Which Ethereum network the canister is connected to (Mainnet or Sepolia).
+    /// Which Ethereum network or EVM chain the canister is connected to.
     ethereum_network: EthereumNetwork,
     /// The ECDSA key name used for signing.
     ecdsa_key_name: EcdsaKeyName,
     /// Cached public key derived from the ECDSA key.
-    ecdsa_public_key: Option<EcdsaPublicKey>
+    ecdsa_public_key: Option<EcdsaPublicKey>,
+    /// Upper bound on the estimated `max_fee_per_gas`, in wei.
+    max_fee_per_gas_ceiling: Option<u128>,
+    /// Percentage added on top of the `eth_estimateGas` result.
+    gas_limit_buffer_percent: Option<u8>,
+    /// RPC providers to query, in order of preference.
+    rpc_providers: Option<Vec<RpcProvider>>,
+    /// JSON-RPC endpoints queried instead of the RPC providers.
+    custom_rpc_services: Option<Vec<RpcApi>>,
+    /// How different results from the RPC providers are reconciled.
+    consensus_strategy: Option<ConsensusStrategy>,
+    /// How signed transactions are sent to the RPC providers.
+    broadcast_strategy: Option<BroadcastStrategy>,
+    /// Other chains the canister operates on, by chain id.
+    additional_chains: BTreeMap<u64, ChainConfig>,
+    /// Cycles attached to each call to the EVM RPC canister.
+    evm_rpc_cycles: Option<u128>,
+    /// Number of blocks after which a pending transaction is replaced with higher fees.
+    fee_bump_after_blocks: Option<u64>,
+    /// Maximum number of automatic fee bumps of a single transaction.
+    max_fee_bumps: Option<u8>,
+    /// Nonces handed out per chain and address (not persisted, see `NonceTracker`).
+    nonces: BTreeMap<(u64, Address), NonceTracker>,
+    /// Owners with a send in progress (see `SendGuard`).
+    active_sends: BTreeSet<Principal>,
+    /// Periodic tasks currently running (see `TimerGuard`).
+    active_tasks: BTreeSet<TimerTask>,
+    /// Transactions sent by the canister whose status can still change, by transaction hash.
+    /// Once final, they are only kept in the history.
+    transactions: BTreeMap<String, SentTransaction>,
+    /// Number of broadcasts so far, used to rotate the first provider tried (not persisted).
+    broadcast_count: usize,
+    /// Number of confirmations required before a deposit is credited.
+    deposit_confirmations: Option<u64>,
+    /// Blocks considered safe from reorgs.
+    finality_tag: Option<FinalityTag>,
+    /// Addresses scanned for deposits, by chain id and owner.
+    deposit_accounts: BTreeMap<(u64, Principal), DepositAccount>,
+    /// Last block scanned for deposits, by chain id.
+    deposit_scanned_blocks: BTreeMap<u64, u64>,
+    /// Deposits in a block that could still be reorged, by owner and index.
+    pending_deposits: BTreeSet<(Principal, u64)>,
+    /// Canisters notified of deposits, with the method to call.
+    subscriptions: BTreeMap<Principal, String>,
+    /// Deposit notifications not delivered yet, by id.
+    notifications: BTreeMap<u64, QueuedNotification>,
+    /// Id of the next queued notification, so that ids are never reused.
+    next_notification_id: u64,
+    /// Number of deliveries awaiting the reply of each subscriber (see `DeliveryGuard`).
+    active_deliveries: BTreeMap<Principal, usize>,
 }
 
-
-impl State{
+impl State {
     /// Construct the management canister ECDSA key identifier from the stored key name.
-    pub fn ecdsa_key_id(&self) -> EcdsaKeyId{
+    pub fn ecdsa_key_id(&self) -> EcdsaKeyId {
         EcdsaKeyId::from(&self.ecdsa_key_name)
     }
-    /// Return the current Ethereum network.
-    pub fn ethereum_network(&self) -> EthereumNetwork {
-        self.ethereum_network
+
+    /// Return the ceiling applied to the estimated `max_fee_per_gas`.
+    pub fn max_fee_per_gas_ceiling(&self) -> u128 {
+        self.max_fee_per_gas_ceiling
+            .unwrap_or(DEFAULT_MAX_FEE_PER_GAS_CEILING)
+    }
+
+    /// Return the buffer, in percent, applied to gas estimates.
+    pub fn gas_limit_buffer_percent(&self) -> u8 {
+        self.gas_limit_buffer_percent
+            .unwrap_or(DEFAULT_GAS_LIMIT_BUFFER_PERCENT)
+    }
+
+    /// Return the cycles attached to each call to the EVM RPC canister.
+    pub fn evm_rpc_cycles(&self) -> u128 {
+        self.evm_rpc_cycles.unwrap_or(DEFAULT_EVM_RPC_CYCLES)
+    }
+
+    /// Return after how many blocks a pending transaction gets its fees bumped,
+    /// or `None` if automatic fee bumping is disabled.
+    pub fn fee_bump_after_blocks(&self) -> Option<u64> {
+        self.fee_bump_after_blocks.filter(|blocks| *blocks > 0)
+    }
+
+    /// Return the maximum number of automatic fee bumps of a single transaction.
+    pub fn max_fee_bumps(&self) -> u8 {
+        self.max_fee_bumps.unwrap_or(DEFAULT_MAX_FEE_BUMPS)
+    }
+
+    /// Return the number of confirmations required before a deposit is credited.
+    pub fn deposit_confirmations(&self) -> u64 {
+        self.deposit_confirmations
+            .unwrap_or(DEFAULT_DEPOSIT_CONFIRMATIONS)
+    }
+
+    pub fn finality_tag(&self) -> FinalityTag {
+        self.finality_tag.unwrap_or_default()
+    }
+
+    /// Return the chain the canister was deployed for, used when no chain id is given.
+    pub fn default_chain(&self) -> ChainConfig {
+        ChainConfig {
+            ethereum_network: self.ethereum_network.clone(),
+            rpc_providers: self.rpc_providers.clone(),
+            custom_rpc_services: self.custom_rpc_services.clone(),
+            consensus_strategy: self.consensus_strategy.clone(),
+            broadcast_strategy: self.broadcast_strategy.clone(),
+        }
+    }
+
+    /// Return the number of broadcasts so far and count a new one.
+    pub fn next_broadcast_offset(&mut self) -> usize {
+        let offset = self.broadcast_count;
+        self.broadcast_count = self.broadcast_count.wrapping_add(1);
+        offset
+    }
+
+    /// Return the configuration of the chain, or of the default chain if no chain id is given.
+    pub fn chain(&self, chain_id: Option<u64>) -> Result<ChainConfig, WalletError> {
+        let default_chain = self.default_chain();
+        match chain_id {
+            None => Ok(default_chain),
+            Some(chain_id) if chain_id == default_chain.chain_id() => Ok(default_chain),
+            Some(chain_id) => self
+                .additional_chains
+                .get(&chain_id)
+                .cloned()
+                .ok_or(WalletError::UnsupportedChain(chain_id)),
+        }
+    }
+
+    /// Return the nonce tracker of the address on the chain, creating it if needed.
+    pub fn nonce_tracker_mut(&mut self, chain_id: u64, address: Address) -> &mut NonceTracker {
+        self.nonces.entry((chain_id, address)).or_default()
+    }
+
+    /// Mark a send of the owner as in progress. Returns `false` if one already is.
+    pub fn try_lock_send(&mut self, owner: Principal) -> bool {
+        self.active_sends.insert(owner)
+    }
+
+    /// Mark the send of the owner as finished.
+    pub fn unlock_send(&mut self, owner: &Principal) {
+        self.active_sends.remove(owner);
     }
 
-     /// Return RPC services available for the current Ethereum network.
-    pub fn evm_rpc_services(&self) -> RpcServices {
-        match self.ethereum_network {
-            EthereumNetwork::Mainnet => RpcServices::EthMainnet(none),
-            EthereumNetwork::Sepolia => RpcServices::EthSepolia(none),
+    /// Mark a periodic task as running. Returns `false` if it already is.
+    pub fn try_lock_task(&mut self, task: TimerTask) -> bool {
+        self.active_tasks.insert(task)
     }
 
-    /// Return a single RPC service (public node) for the current Ethereum network.
-    pub fn singel_rpc_service(&self) -> RpcServices {
-        match self.ethereum_network {
-            EthereumNetwork::EthMainnet => {
-                Some(Vec![EthMainnetService::PublicNode])
+    /// Mark a periodic task as finished.
+    pub fn unlock_task(&mut self, task: TimerTask) {
+        self.active_tasks.remove(&task);
+    }
+
+    /// Mark a delivery to the subscriber as started.
+    /// Returns `false` if `max` deliveries to the subscriber are already in flight.
+    pub fn try_start_delivery(&mut self, subscriber: Principal, max: usize) -> bool {
+        let active = self.active_deliveries.entry(subscriber).or_default();
+        if *active >= max {
+            return false;
+        }
+        *active += 1;
+        true
+    }
+
+    /// Mark a delivery to the subscriber as finished.
+    pub fn finish_delivery(&mut self, subscriber: &Principal) {
+        if let Some(active) = self.active_deliveries.get_mut(subscriber) {
+            *active = active.saturating_sub(1);
+            if *active == 0 {
+                self.active_deliveries.remove(subscriber);
             }
-            EthereumNetwork::EthSepolia => {
-                Some(Vec![EthSepoliaService::PublicNode])
+        }
+    }
+
+    /// Record a transaction that was just sent.
+    pub fn record_sent_transaction(&mut self, tx_hash: String, transaction: SentTransaction) {
+        self.transactions.insert(tx_hash, transaction);
+    }
+
+    /// Return the status of a transaction sent by the canister.
+    pub fn transaction_status(&self, tx_hash: &str) -> Option<TransactionStatus> {
+        self.transactions
+            .get(&tx_hash.to_lowercase())
+            .map(|tx| tx.status.clone())
+    }
+
+    pub fn sent_transaction(&self, tx_hash: &str) -> Option<SentTransaction> {
+        self.transactions.get(&tx_hash.to_lowercase()).cloned()
+    }
+
+    /// Return the transactions whose status is still pending.
+    pub fn pending_transactions(&self) -> Vec<(String, SentTransaction)> {
+        self.transactions
+            .iter()
+            .filter(|(_, tx)| tx.status == TransactionStatus::Pending)
+            .map(|(hash, tx)| (hash.clone(), tx.clone()))
+            .collect()
+    }
+
+    /// Return the transactions that are pending or mined in a block that could still be reorged.
+    pub fn unfinalized_transactions(&self) -> Vec<(String, SentTransaction)> {
+        self.transactions
+            .iter()
+            .filter(|(_, tx)| match tx.status {
+                TransactionStatus::Pending => true,
+                TransactionStatus::Mined { .. } | TransactionStatus::Reverted { .. } => {
+                    tx.finalized_at_block.is_none()
+                }
+                TransactionStatus::Dropped | TransactionStatus::Rejected { .. } => false,
+            })
+            .map(|(hash, tx)| (hash.clone(), tx.clone()))
+            .collect()
+    }
+
+    pub fn set_transaction_finalized(&mut self, tx_hash: &str, block_number: u64) {
+        if let Some(tx) = self.transactions.get_mut(tx_hash) {
+            tx.finalized_at_block = Some(block_number);
+        }
+    }
+
+    /// Return the nonces of the pending transactions sent from the address on the chain.
+    pub fn pending_nonces(&self, chain_id: u64, from: &Address) -> BTreeSet<u64> {
+        let from = from.to_string();
+        self.transactions
+            .values()
+            .filter(|tx| {
+                tx.chain_id == chain_id
+                    && tx.status == TransactionStatus::Pending
+                    && tx.from.eq_ignore_ascii_case(&from)
+            })
+            .map(|tx| tx.nonce)
+            .collect()
+    }
+
+    /// Stop tracking the transactions whose status can no longer change, i.e. dropped
+    /// transactions and transactions in a finalized block, and return them.
+    pub fn take_final_transactions(&mut self) -> Vec<(String, SentTransaction)> {
+        let final_hashes: Vec<String> = self
+            .transactions
+            .iter()
+            .filter(|(_, tx)| match tx.status {
+                TransactionStatus::Pending => false,
+                TransactionStatus::Mined { .. } | TransactionStatus::Reverted { .. } => {
+                    tx.finalized_at_block.is_some()
+                }
+                TransactionStatus::Dropped | TransactionStatus::Rejected { .. } => true,
+            })
+            .map(|(hash, _)| hash.clone())
+            .collect();
+        final_hashes
+            .into_iter()
+            .filter_map(|hash| self.transactions.remove_entry(&hash))
+            .collect()
+    }
+
+    /// Return the pending transactions that were not replaced yet.
+    pub fn replaceable_transactions(&self) -> Vec<(String, SentTransaction)> {
+        self.pending_transactions()
+            .into_iter()
+            .filter(|(_, tx)| tx.replaced_by.is_none())
+            .collect()
+    }
+
+    pub fn mark_transaction_replaced(&mut self, tx_hash: &str, replaced_by: String) {
+        if let Some(tx) = self.transactions.get_mut(tx_hash) {
+            tx.replaced_by = Some(replaced_by);
+        }
+    }
+
+    pub fn set_pending_since_block(&mut self, tx_hash: &str, block_number: u64) {
+        if let Some(tx) = self.transactions.get_mut(tx_hash) {
+            tx.pending_since_block.get_or_insert(block_number);
+        }
+    }
+
+    pub fn update_transaction_status(&mut self, tx_hash: &str, status: TransactionStatus) {
+        if let Some(tx) = self.transactions.get_mut(tx_hash) {
+            tx.status = status;
+        }
+    }
+
+    /// Start scanning the address of the owner for deposits on the chain.
+    /// Registering an address twice has no effect.
+    pub fn register_deposit_account(&mut self, account: DepositAccount) -> Result<(), WalletError> {
+        let key = (account.chain_id, account.owner);
+        if self.deposit_accounts.contains_key(&key) {
+            return Ok(());
+        }
+        let registered = self
+            .deposit_accounts
+            .keys()
+            .filter(|(chain_id, _)| *chain_id == account.chain_id)
+            .count();
+        if registered >= MAX_DEPOSIT_ACCOUNTS_PER_CHAIN {
+            return Err(WalletError::TooManyDepositAddresses {
+                chain_id: account.chain_id,
+                max: MAX_DEPOSIT_ACCOUNTS_PER_CHAIN as u64,
+            });
+        }
+        self.deposit_accounts.insert(key, account);
+        Ok(())
+    }
+
+    pub fn unregister_deposit_account(&mut self, chain_id: u64, owner: &Principal) {
+        self.deposit_accounts.remove(&(chain_id, *owner));
+    }
+
+    /// Return the ids of the chains with addresses scanned for deposits.
+    pub fn deposit_chains(&self) -> BTreeSet<u64> {
+        self.deposit_accounts
+            .keys()
+            .map(|(chain_id, _)| *chain_id)
+            .collect()
+    }
+
+    pub fn deposit_accounts(&self, chain_id: u64) -> Vec<DepositAccount> {
+        self.deposit_accounts
+            .iter()
+            .filter(|((id, _), _)| *id == chain_id)
+            .map(|(_, account)| account.clone())
+            .collect()
+    }
+
+    pub fn set_deposit_balance(&mut self, chain_id: u64, owner: Principal, balance: Nat) {
+        if let Some(account) = self.deposit_accounts.get_mut(&(chain_id, owner)) {
+            account.eth_balance = Some(balance);
+        }
+    }
+
+    pub fn deposit_scanned_block(&self, chain_id: u64) -> Option<u64> {
+        self.deposit_scanned_blocks.get(&chain_id).copied()
+    }
+
+    pub fn set_deposit_scanned_block(&mut self, chain_id: u64, block_number: u64) {
+        self.deposit_scanned_blocks.insert(chain_id, block_number);
+    }
+
+    /// Scan the blocks after the given one again, e.g. after a reorg.
+    pub fn rewind_deposit_scan(&mut self, chain_id: u64, block_number: u64) {
+        if let Some(scanned_block) = self.deposit_scanned_blocks.get_mut(&chain_id) {
+            *scanned_block = (*scanned_block).min(block_number);
+        }
+    }
+
+    /// Lower the last known ETH balance of the owner, e.g. after a deposit was reorged out.
+    pub fn deduct_deposit_balance(&mut self, chain_id: u64, owner: Principal, amount: &Nat) {
+        if let Some(account) = self.deposit_accounts.get_mut(&(chain_id, owner)) {
+            if let Some(balance) = &account.eth_balance {
+                account.eth_balance = Some(if balance > amount {
+                    balance.clone() - amount.clone()
+                } else {
+                    Nat::from(0_u8)
+                });
             }
         }
     }
+
+    pub fn pending_deposits(&self) -> Vec<(Principal, u64)> {
+        self.pending_deposits.iter().copied().collect()
+    }
+
+    pub fn add_pending_deposit(&mut self, owner: Principal, index: u64) {
+        self.pending_deposits.insert((owner, index));
+    }
+
+    pub fn remove_pending_deposit(&mut self, owner: Principal, index: u64) {
+        self.pending_deposits.remove(&(owner, index));
+    }
+
+    /// Notify the canister of deposits by calling the given method.
+    /// Subscribing again replaces the method.
+    pub fn subscribe(&mut self, subscriber: Principal, method: String) {
+        self.subscriptions.insert(subscriber, method);
+    }
+
+    /// Stop notifying the canister, dropping the notifications not delivered yet.
+    pub fn unsubscribe(&mut self, subscriber: &Principal) {
+        self.subscriptions.remove(subscriber);
+        self.notifications
+            .retain(|_, queued| &queued.subscriber != subscriber);
+    }
+
+    pub fn subscription(&self, subscriber: &Principal) -> Option<String> {
+        self.subscriptions.get(subscriber).cloned()
+    }
+
+    pub fn subscribers(&self) -> Vec<Principal> {
+        self.subscriptions.keys().copied().collect()
+    }
+
+    /// Queue a notification, dropping the oldest ones to keep at most `max_queued` of them.
+    /// Returns the dropped notifications.
+    pub fn queue_notification(
+        &mut self,
+        notification: QueuedNotification,
+        max_queued: usize,
+    ) -> Vec<QueuedNotification> {
+        let id = self.next_notification_id;
+        self.next_notification_id += 1;
+        self.notifications.insert(id, notification);
+        let mut dropped = vec![];
+        while self.notifications.len() > max_queued {
+            match self.notifications.pop_first() {
+                Some((_, oldest)) => dropped.push(oldest),
+                None => break,
+            }
+        }
+        dropped
+    }
+
+    /// Return the notifications whose next delivery attempt is due at the given time.
+    pub fn due_notifications(&self, now: u64) -> Vec<(u64, QueuedNotification)> {
+        self.notifications
+            .iter()
+            .filter(|(_, queued)| queued.next_attempt_at <= now)
+            .map(|(id, queued)| (*id, queued.clone()))
+            .collect()
+    }
+
+    pub fn reschedule_notification(&mut self, id: u64, attempts: u32, next_attempt_at: u64) {
+        if let Some(queued) = self.notifications.get_mut(&id) {
+            queued.attempts = attempts;
+            queued.next_attempt_at = next_attempt_at;
+        }
+    }
+
+    pub fn remove_notification(&mut self, id: u64) {
+        self.notifications.remove(&id);
+    }
+
+    /// Apply the configuration changes of an upgrade.
+    /// Nothing is changed if the upgrade argument is invalid.
+    pub fn upgrade(&mut self, upgrade_arg: UpgradeArg) -> Result<(), String> {
+        let ethereum_network = upgrade_arg
+            .ethereum_network
+            .clone()
+            .unwrap_or_else(|| self.ethereum_network.clone());
+        if let Some(ecdsa_key_name) = &upgrade_arg.ecdsa_key_name {
+            if ecdsa_key_name != &self.ecdsa_key_name
+                && !upgrade_arg.allow_address_change.unwrap_or_default()
+            {
+                return Err(format!(
+                    "changing the ECDSA key from {:?} to {:?} changes the address of every user. \
+                    Set `allow_address_change` to proceed anyway",
+                    self.ecdsa_key_name, ecdsa_key_name
+                ));
+            }
+        }
+        // Providers and endpoints configured for the previous network may not exist on the new one.
+        // Setting either providers or custom endpoints replaces the other.
+        let keep_rpc_config = ethereum_network == self.ethereum_network
+            && upgrade_arg.rpc_providers.is_none()
+            && upgrade_arg.custom_rpc_services.is_none();
+        let (rpc_providers, custom_rpc_services) = if keep_rpc_config {
+            (self.rpc_providers.clone(), self.custom_rpc_services.clone())
+        } else {
+            (upgrade_arg.rpc_providers, upgrade_arg.custom_rpc_services)
+        };
+        let default_chain = ChainConfig {
+            ethereum_network,
+            rpc_providers,
+            custom_rpc_services,
+            consensus_strategy: upgrade_arg
+                .consensus_strategy
+                .or_else(|| self.consensus_strategy.clone()),
+            broadcast_strategy: upgrade_arg
+                .broadcast_strategy
+                .or_else(|| self.broadcast_strategy.clone()),
+        };
+        let additional_chains = match upgrade_arg.additional_chains {
+            Some(chains) => validate_chains(&default_chain, &chains)?,
+            None => validate_chains(
+                &default_chain,
+                &self.additional_chains.values().cloned().collect::<Vec<_>>(),
+            )?,
+        };
+        let removed_chains: BTreeSet<u64> = self
+            .additional_chains
+            .keys()
+            .copied()
+            .chain(std::iter::once(self.default_chain().chain_id()))
+            .filter(|chain_id| {
+                *chain_id != default_chain.chain_id() && !additional_chains.contains_key(chain_id)
+            })
+            .collect();
+        let has_tracked_entries = |chain_id: &u64| {
+            self.transactions.values().any(|tx| tx.chain_id == *chain_id)
+                || self.deposit_accounts.keys().any(|(id, _)| id == chain_id)
+        };
+        if let Some(chain_id) = removed_chains.iter().find(|id| has_tracked_entries(id)) {
+            if !upgrade_arg.allow_chain_removal.unwrap_or_default() {
+                return Err(format!(
+                    "removing chain {} drops its pending transactions and deposit addresses. \
+                    Set `allow_chain_removal` to proceed anyway",
+                    chain_id
+                ));
+            }
+        }
+        let max_fee_per_gas_ceiling = upgrade_arg
+            .max_fee_per_gas_ceiling
+            .map(crate::nat_to_u128)
+            .transpose()
+            .map_err(|e| format!("invalid max fee per gas ceiling: {:?}", e))?;
+        let evm_rpc_cycles = upgrade_arg
+            .evm_rpc_cycles
+            .map(crate::nat_to_u128)
+            .transpose()
+            .map_err(|e| format!("invalid EVM RPC cycles: {:?}", e))?;
+
+        if let Some(ecdsa_key_name) = upgrade_arg.ecdsa_key_name {
+            if ecdsa_key_name != self.ecdsa_key_name {
+                self.ecdsa_key_name = ecdsa_key_name;
+                self.ecdsa_public_key = None;
+                // The registered addresses were derived from the previous key.
+                // Pending transactions and deposits are still tracked until final,
+                // but transactions sent from the previous addresses can no longer be replaced.
+                self.deposit_accounts.clear();
+            }
+        }
+        self.transactions
+            .retain(|_, tx| !removed_chains.contains(&tx.chain_id));
+        self.deposit_accounts
+            .retain(|(chain_id, _), _| !removed_chains.contains(chain_id));
+        self.ethereum_network = default_chain.ethereum_network;
+        self.rpc_providers = default_chain.rpc_providers;
+        self.custom_rpc_services = default_chain.custom_rpc_services;
+        self.consensus_strategy = default_chain.consensus_strategy;
+        self.broadcast_strategy = default_chain.broadcast_strategy;
+        self.additional_chains = additional_chains;
+        if let Some(ceiling) = max_fee_per_gas_ceiling {
+            self.max_fee_per_gas_ceiling = Some(ceiling);
+        }
+        if let Some(buffer_percent) = upgrade_arg.gas_limit_buffer_percent {
+            self.gas_limit_buffer_percent = Some(buffer_percent);
+        }
+        if let Some(cycles) = evm_rpc_cycles {
+            self.evm_rpc_cycles = Some(cycles);
+        }
+        if let Some(blocks) = upgrade_arg.fee_bump_after_blocks {
+            self.fee_bump_after_blocks = Some(blocks);
+        }
+        if let Some(max_fee_bumps) = upgrade_arg.max_fee_bumps {
+            self.max_fee_bumps = Some(max_fee_bumps);
+        }
+        if let Some(confirmations) = upgrade_arg.deposit_confirmations {
+            self.deposit_confirmations = Some(confirmations);
+        }
+        if let Some(finality_tag) = upgrade_arg.finality_tag {
+            self.finality_tag = Some(finality_tag);
+        }
+        Ok(())
+    }
+}
+
+/// Check that a provider selection is not empty and only contains providers supporting the network.
+fn validate_rpc_providers(
+    ethereum_network: &EthereumNetwork,
+    providers: &[RpcProvider],
+) -> Result<(), String> {
+    if let EthereumNetwork::Custom { .. } = ethereum_network {
+        return Err("custom networks use their own RPC services instead of providers".to_string());
+    }
+    if providers.is_empty() {
+        return Err("at least one RPC provider must be configured".to_string());
+    }
+    if let Some(provider) = providers
+        .iter()
+        .find(|provider| ethereum_network.rpc_service(**provider).is_none())
+    {
+        return Err(format!(
+            "RPC provider {:?} does not support {:?}",
+            provider, ethereum_network
+        ));
+    }
+    Ok(())
+}
+
+/// Check the configuration of every chain and that no two chains share a chain id.
+/// Returns the additional chains by chain id.
+fn validate_chains(
+    default_chain: &ChainConfig,
+    additional_chains: &[ChainConfig],
+) -> Result<BTreeMap<u64, ChainConfig>, String> {
+    let mut chains = BTreeMap::new();
+    for chain in std::iter::once(default_chain).chain(additional_chains) {
+        validate_ethereum_network(&chain.ethereum_network)?;
+        if let Some(providers) = &chain.rpc_providers {
+            validate_rpc_providers(&chain.ethereum_network, providers)?;
+        }
+        if let Some(rpc_services) = &chain.custom_rpc_services {
+            if rpc_services.is_empty() || chain.rpc_providers.is_some() {
+                return Err(format!(
+                    "custom RPC services of chain {} must not be empty and cannot be combined with RPC providers",
+                    chain.chain_id()
+                ));
+            }
+        }
+        if let ConsensusStrategy::Threshold { min } = chain.consensus_strategy() {
+            let total = chain.json_rpc_services().len();
+            if min == 0 || min as usize > total {
+                return Err(format!(
+                    "consensus threshold {} of chain {} must be between 1 and the number of RPC services ({})",
+                    min,
+                    chain.chain_id(),
+                    total
+                ));
+            }
+        }
+        if chains.insert(chain.chain_id(), chain.clone()).is_some() {
+            return Err(format!("chain id {} is configured twice", chain.chain_id()));
+        }
+    }
+    chains.remove(&default_chain.chain_id());
+    Ok(chains)
+}
+
+/// Check that a custom network has a chain id and at least one RPC service.
+fn validate_ethereum_network(ethereum_network: &EthereumNetwork) -> Result<(), String> {
+    if let EthereumNetwork::Custom {
+        chain_id,
+        rpc_services,
+    } = ethereum_network
+    {
+        if *chain_id == 0 {
+            return Err("the chain id of a custom network must not be zero".to_string());
+        }
+        if rpc_services.is_empty() {
+            return Err("at least one RPC service must be configured for a custom network".to_string());
+        }
+    }
+    Ok(())
+}
+
+/// Serialized form of the state kept in stable memory across upgrades.
+/// Each variant is a schema version: optional fields can be added to the latest
+/// variant, any other change means adding a new variant and migrating the older
+/// ones when restoring.
+#[derive(CandidType, Deserialize, Debug, Default, Clone)]
+enum StableState {
+    /// Nothing was saved yet (e.g. the first upgrade of a canister installed without stable state).
+    #[default]
+    Empty,
+    V1(StableStateV1),
+}
+
+#[derive(CandidType, Deserialize, Debug, Clone)]
+struct StableStateV1 {
+    ethereum_network: EthereumNetwork,
+    ecdsa_key_name: EcdsaKeyName,
+    ecdsa_public_key: Option<EcdsaPublicKeyResponse>,
+    max_fee_per_gas_ceiling: Option<Nat>,
+    gas_limit_buffer_percent: Option<u8>,
+    rpc_providers: Option<Vec<RpcProvider>>,
+    custom_rpc_services: Option<Vec<RpcApi>>,
+    consensus_strategy: Option<ConsensusStrategy>,
+    evm_rpc_cycles: Option<Nat>,
+    transactions: Option<Vec<(String, SentTransaction)>>,
+    fee_bump_after_blocks: Option<u64>,
+    max_fee_bumps: Option<u8>,
+    additional_chains: Option<Vec<ChainConfig>>,
+    broadcast_strategy: Option<BroadcastStrategy>,
+    deposit_confirmations: Option<u64>,
+    deposit_accounts: Option<Vec<DepositAccount>>,
+    deposit_scanned_blocks: Option<Vec<(u64, u64)>>,
+    finality_tag: Option<FinalityTag>,
+    pending_deposits: Option<Vec<(Principal, u64)>>,
+    subscriptions: Option<Vec<(Principal, String)>>,
+    notifications: Option<Vec<(u64, QueuedNotification)>>,
+    next_notification_id: Option<u64>,
+}
+
+impl Storable for StableState {
+    fn to_bytes(&self) -> Cow<[u8]> {
+        Cow::Owned(Encode!(self).expect("failed to encode the state"))
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).expect("failed to decode the state")
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl From<&State> for StableState {
+    fn from(state: &State) -> Self {
+        StableState::V1(StableStateV1 {
+            ethereum_network: state.ethereum_network.clone(),
+            ecdsa_key_name: state.ecdsa_key_name.clone(),
+            ecdsa_public_key: state.ecdsa_public_key.as_ref().map(EcdsaPublicKeyResponse::from),
+            max_fee_per_gas_ceiling: state.max_fee_per_gas_ceiling.map(Nat::from),
+            gas_limit_buffer_percent: state.gas_limit_buffer_percent,
+            rpc_providers: state.rpc_providers.clone(),
+            custom_rpc_services: state.custom_rpc_services.clone(),
+            consensus_strategy: state.consensus_strategy.clone(),
+            evm_rpc_cycles: state.evm_rpc_cycles.map(Nat::from),
+            transactions: Some(
+                state
+                    .transactions
+                    .iter()
+                    .map(|(hash, tx)| (hash.clone(), tx.clone()))
+                    .collect(),
+            ),
+            fee_bump_after_blocks: state.fee_bump_after_blocks,
+            max_fee_bumps: state.max_fee_bumps,
+            additional_chains: Some(state.additional_chains.values().cloned().collect()),
+            broadcast_strategy: state.broadcast_strategy.clone(),
+            deposit_confirmations: state.deposit_confirmations,
+            deposit_accounts: Some(state.deposit_accounts.values().cloned().collect()),
+            deposit_scanned_blocks: Some(
+                state
+                    .deposit_scanned_blocks
+                    .iter()
+                    .map(|(chain_id, block_number)| (*chain_id, *block_number))
+                    .collect(),
+            ),
+            finality_tag: state.finality_tag,
+            pending_deposits: Some(state.pending_deposits.iter().copied().collect()),
+            subscriptions: Some(
+                state
+                    .subscriptions
+                    .iter()
+                    .map(|(subscriber, method)| (*subscriber, method.clone()))
+                    .collect(),
+            ),
+            notifications: Some(
+                state
+                    .notifications
+                    .iter()
+                    .map(|(id, queued)| (*id, queued.clone()))
+                    .collect(),
+            ),
+            next_notification_id: Some(state.next_notification_id),
+        })
+    }
+}
+
+impl From<StableState> for State {
+    fn from(stable_state: StableState) -> Self {
+        match stable_state {
+            StableState::Empty => State::default(),
+            StableState::V1(v1) => State {
+                ethereum_network: v1.ethereum_network,
+                ecdsa_key_name: v1.ecdsa_key_name,
+                ecdsa_public_key: v1.ecdsa_public_key.map(EcdsaPublicKey::from),
+                max_fee_per_gas_ceiling: v1.max_fee_per_gas_ceiling.map(config_nat_to_u128),
+                gas_limit_buffer_percent: v1.gas_limit_buffer_percent,
+                rpc_providers: v1.rpc_providers,
+                custom_rpc_services: v1.custom_rpc_services,
+                consensus_strategy: v1.consensus_strategy,
+                broadcast_strategy: v1.broadcast_strategy,
+                deposit_confirmations: v1.deposit_confirmations,
+                deposit_accounts: v1
+                    .deposit_accounts
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|account| ((account.chain_id, account.owner), account))
+                    .collect(),
+                deposit_scanned_blocks: v1
+                    .deposit_scanned_blocks
+                    .unwrap_or_default()
+                    .into_iter()
+                    .collect(),
+                finality_tag: v1.finality_tag,
+                pending_deposits: v1.pending_deposits.unwrap_or_default().into_iter().collect(),
+                subscriptions: v1.subscriptions.unwrap_or_default().into_iter().collect(),
+                // Before `notifications` is moved: older states did not persist the counter.
+                next_notification_id: v1.next_notification_id.unwrap_or_else(|| {
+                    v1.notifications
+                        .iter()
+                        .flatten()
+                        .map(|(id, _)| id + 1)
+                        .max()
+                        .unwrap_or_default()
+                }),
+                notifications: v1.notifications.unwrap_or_default().into_iter().collect(),
+                evm_rpc_cycles: v1.evm_rpc_cycles.map(config_nat_to_u128),
+                transactions: v1.transactions.unwrap_or_default().into_iter().collect(),
+                fee_bump_after_blocks: v1.fee_bump_after_blocks,
+                max_fee_bumps: v1.max_fee_bumps,
+                additional_chains: v1
+                    .additional_chains
+                    .unwrap_or_default()
+                    .into_iter()
+                    .map(|chain| (chain.chain_id(), chain))
+                    .collect(),
+                ..Default::default()
+            },
+        }
+    }
+}
+
+fn stable_state_cell() -> StableCell<StableState, VMem> {
+    StableCell::init(state_memory(), StableState::default())
+        .unwrap_or_else(|e| ic_cdk::trap(&format!("failed to initialize stable state: {:?}", e)))
 }
 
+/// Write the global state to stable memory (called before an upgrade).
+pub fn save_state() {
+    let stable_state = read_state(StableState::from);
+    stable_state_cell()
+        .set(stable_state)
+        .unwrap_or_else(|e| ic_cdk::trap(&format!("failed to save state: {:?}", e)));
+}
+
+/// Restore the global state from stable memory (called after an upgrade).
+pub fn restore_state() {
+    let stable_state = stable_state_cell().get().clone();
+    STATE.with(|s| *s.borrow_mut() = State::from(stable_state));
+}
 
 /// Initialize state from InitArg (used at canister init).
 impl From<InitArg> for State {
@@ -76,41 +894,340 @@ impl From<InitArg> for State {
         State {
             ethereum_network: value.ethereum_network.unwrap_or_default(),
             ecdsa_key_name: value.ecdsa_key_name.unwrap_or_default(),
+            max_fee_per_gas_ceiling: value.max_fee_per_gas_ceiling.map(config_nat_to_u128),
+            gas_limit_buffer_percent: value.gas_limit_buffer_percent,
+            rpc_providers: value.rpc_providers,
+            custom_rpc_services: value.custom_rpc_services,
+            consensus_strategy: value.consensus_strategy,
+            broadcast_strategy: value.broadcast_strategy,
+            deposit_confirmations: value.deposit_confirmations,
+            finality_tag: value.finality_tag,
+            evm_rpc_cycles: value.evm_rpc_cycles.map(config_nat_to_u128),
+            fee_bump_after_blocks: value.fee_bump_after_blocks,
+            max_fee_bumps: value.max_fee_bumps,
+            additional_chains: value
+                .additional_chains
+                .unwrap_or_default()
+                .into_iter()
+                .map(|chain| (chain.chain_id(), chain))
+                .collect(),
             ..Default::default()
         }
     }
 }
 
+/// Convert a configuration value, trapping if it is out of range
+/// (configuration is only set at init and upgrade, where trapping aborts the operation).
+fn config_nat_to_u128(nat: Nat) -> u128 {
+    crate::nat_to_u128(nat)
+        .unwrap_or_else(|e| ic_cdk::trap(&format!("invalid configuration value: {:?}", e)))
+}
 
 /// Lazily fetch the ECDSA public key from the management canister.
 /// - If it's already cached in state, return it.
 /// - Otherwise, call the management canister, cache it, and return it.
-pub async fn lazy_call_ecdsa_public_key() -> EcdsaPublicKey {
+pub async fn lazy_call_ecdsa_public_key() -> Result<EcdsaPublicKey, WalletError> {
     use ic_cdk::api::management_canister::ecdsa::{ecdsa_public_key, EcdsaPublicKeyArgument};
 
     // If cached public key exists, return it
-    if let some(ecdsa_pk) = read_state(|s| s.ecdsa_public_key.clone()) {
-        return ecdsa_pk;
+    if let Some(ecdsa_pk) = read_state(|s| s.ecdsa_public_key.clone()) {
+        return Ok(ecdsa_pk);
     }
 
     // Otherwise, fetch from management canister
     let key_id = read_state(|s| s.ecdsa_key_id());
-    let (pk_response,) = ecdsa_public_key(EcdsaPublicKeyArgument{
-        canister_id: none,
+    let (pk_response,) = ecdsa_public_key(EcdsaPublicKeyArgument {
+        canister_id: None,
         key_id,
         derivation_path: vec![],
     })
     .await
-    .unwrap_or_else(|(error_code, message)| {
-        ic_cdk::trap(&format!("Failed to fetch ECDSA public key: {}: {}", error_code, message))
-    });
+    .map_err(|(error_code, message)| {
+        WalletError::EcdsaKeyUnavailable(format!(
+            "Failed to fetch ECDSA public key: {:?}: {}",
+            error_code, message
+        ))
+    })?;
 
-    
     // Convert response into EcdsaPublicKey and cache it
-    let pk = EcdsaPublicKey::from(response);
+    let pk = EcdsaPublicKey::from(pk_response);
     mutate_state(|s| s.ecdsa_public_key = Some(pk.clone()));
-    pk
+    Ok(pk)
 }
 
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn should_refuse_ecdsa_key_change_without_allow_address_change() {
+        let mut state = State::default();
+        let result = state.upgrade(UpgradeArg {
+            ecdsa_key_name: Some(EcdsaKeyName::TestKey1),
+            fee_bump_after_blocks: Some(10),
+            ..Default::default()
+        });
+        assert!(result.is_err());
+        assert_eq!(state, State::default());
+    }
+
+    #[test]
+    fn should_change_ecdsa_key_with_allow_address_change() {
+        let mut state = State::default();
+        let result = state.upgrade(UpgradeArg {
+            ecdsa_key_name: Some(EcdsaKeyName::TestKey1),
+            allow_address_change: Some(true),
+            ..Default::default()
+        });
+        assert_eq!(result, Ok(()));
+        assert_eq!(state.ecdsa_key_name, EcdsaKeyName::TestKey1);
+    }
 
-}
\ No newline at end of file
+    #[test]
+    fn should_keep_ecdsa_key_when_unchanged() {
+        let mut state = State::default();
+        let result = state.upgrade(UpgradeArg {
+            ecdsa_key_name: Some(EcdsaKeyName::TestKeyLocalDevelopment),
+            ..Default::default()
+        });
+        assert_eq!(result, Ok(()));
+        assert_eq!(state.ecdsa_key_name, EcdsaKeyName::TestKeyLocalDevelopment);
+    }
+
+    #[test]
+    fn should_refuse_chain_removal_with_tracked_entries_without_allow_chain_removal() {
+        let mut state = state_with_base_entries();
+        let transactions = state.transactions.clone();
+        let deposit_accounts = state.deposit_accounts.clone();
+        let result = state.upgrade(UpgradeArg {
+            additional_chains: Some(vec![]),
+            ..Default::default()
+        });
+        assert!(result.is_err());
+        assert!(state.chain(Some(8453)).is_ok());
+        assert_eq!(state.transactions, transactions);
+        assert_eq!(state.deposit_accounts, deposit_accounts);
+    }
+
+    #[test]
+    fn should_drop_entries_of_removed_chain_with_allow_chain_removal() {
+        let mut state = state_with_base_entries();
+        let result = state.upgrade(UpgradeArg {
+            additional_chains: Some(vec![]),
+            allow_chain_removal: Some(true),
+            ..Default::default()
+        });
+        assert_eq!(result, Ok(()));
+        assert_eq!(
+            state.chain(Some(8453)),
+            Err(WalletError::UnsupportedChain(8453))
+        );
+        assert!(state.transactions.is_empty());
+        assert!(state.deposit_accounts.is_empty());
+    }
+
+    #[test]
+    fn should_keep_entries_of_remaining_chains() {
+        let mut state = state_with_base_entries();
+        let transactions = state.transactions.clone();
+        let deposit_accounts = state.deposit_accounts.clone();
+        let result = state.upgrade(UpgradeArg {
+            fee_bump_after_blocks: Some(10),
+            ..Default::default()
+        });
+        assert_eq!(result, Ok(()));
+        assert_eq!(state.transactions, transactions);
+        assert_eq!(state.deposit_accounts, deposit_accounts);
+    }
+
+    #[test]
+    fn should_not_reuse_notification_ids() {
+        use crate::deposits::DepositStatus;
+        use crate::notifications::DepositNotification;
+
+        let queued = QueuedNotification {
+            subscriber: Principal::from_slice(&[4, 5, 6, 1]),
+            notification: DepositNotification {
+                owner: Principal::from_slice(&[1, 2, 3]),
+                deposit_index: 0,
+                chain_id: 1,
+                token: None,
+                amount: Nat::from(1_u64),
+                tx_hash: None,
+                block_number: 100,
+                block_hash: "0xabc".to_string(),
+                status: DepositStatus::Confirmed,
+            },
+            attempts: 0,
+            next_attempt_at: 0,
+        };
+        let mut state = State::default();
+        state.queue_notification(queued.clone(), 10);
+        state.queue_notification(queued.clone(), 10);
+        state.remove_notification(0);
+        state.remove_notification(1);
+        state.queue_notification(queued, 10);
+        assert_eq!(
+            state.notifications.keys().copied().collect::<Vec<_>>(),
+            vec![2]
+        );
+    }
+
+    #[test]
+    fn should_limit_deliveries_per_subscriber() {
+        let subscriber = Principal::from_slice(&[4, 5, 6, 1]);
+        let other = Principal::from_slice(&[4, 5, 6, 2]);
+        let mut state = State::default();
+        assert!(state.try_start_delivery(subscriber, 2));
+        assert!(state.try_start_delivery(subscriber, 2));
+        assert!(!state.try_start_delivery(subscriber, 2));
+        assert!(state.try_start_delivery(other, 2));
+        state.finish_delivery(&subscriber);
+        assert!(state.try_start_delivery(subscriber, 2));
+        state.finish_delivery(&subscriber);
+        state.finish_delivery(&subscriber);
+        state.finish_delivery(&other);
+        assert!(state.active_deliveries.is_empty());
+    }
+
+    fn state_with_base_entries() -> State {
+        let owner = Principal::from_slice(&[1, 2, 3]);
+        let mut state = State::default();
+        state
+            .upgrade(UpgradeArg {
+                additional_chains: Some(vec![ChainConfig {
+                    ethereum_network: EthereumNetwork::BaseMainnet,
+                    rpc_providers: None,
+                    custom_rpc_services: None,
+                    consensus_strategy: None,
+                    broadcast_strategy: None,
+                }]),
+                ..Default::default()
+            })
+            .unwrap();
+        state.record_sent_transaction(
+            "0x1234".to_string(),
+            SentTransaction {
+                owner,
+                chain_id: 8453,
+                from: "0x1111111111111111111111111111111111111111".to_string(),
+                nonce: 0,
+                sent_at: 0,
+                status: TransactionStatus::Pending,
+                history_index: 0,
+                fee_bumps: 0,
+                initial_max_fee_per_gas: None,
+                replaced_by: None,
+                pending_since_block: None,
+                finalized_at_block: None,
+            },
+        );
+        state
+            .register_deposit_account(DepositAccount {
+                owner,
+                chain_id: 8453,
+                address: "0x1111111111111111111111111111111111111111".to_string(),
+                eth_balance: None,
+            })
+            .unwrap();
+        state
+    }
+
+    #[test]
+    fn should_round_trip_state_through_stable_memory() {
+        use crate::deposits::DepositStatus;
+        use crate::notifications::DepositNotification;
+
+        let owner = Principal::from_slice(&[1, 2, 3]);
+        let subscriber = Principal::from_slice(&[4, 5, 6, 1]);
+        let rpc_api = RpcApi {
+            url: "https://rpc.example.com".to_string(),
+            headers: None,
+        };
+        let additional_chain = ChainConfig {
+            ethereum_network: EthereumNetwork::BaseMainnet,
+            rpc_providers: Some(vec![RpcProvider::Ankr, RpcProvider::PublicNode]),
+            custom_rpc_services: None,
+            consensus_strategy: Some(ConsensusStrategy::Threshold { min: 1 }),
+            broadcast_strategy: Some(BroadcastStrategy::AllAtOnce),
+        };
+        let sent_transaction = SentTransaction {
+            owner,
+            chain_id: 8453,
+            from: "0x1111111111111111111111111111111111111111".to_string(),
+            nonce: 7,
+            sent_at: 1_700_000_000_000_000_000,
+            status: TransactionStatus::Mined {
+                block_number: Nat::from(100_u64),
+                gas_used: Nat::from(21_000_u64),
+                block_hash: Some("0xabc".to_string()),
+            },
+            history_index: 3,
+            fee_bumps: 1,
+            initial_max_fee_per_gas: Some(Nat::from(20_000_000_000_u64)),
+            replaced_by: Some("0xdef".to_string()),
+            pending_since_block: Some(90),
+            finalized_at_block: Some(110),
+        };
+        let deposit_account = DepositAccount {
+            owner,
+            chain_id: 8453,
+            address: "0x1111111111111111111111111111111111111111".to_string(),
+            eth_balance: Some(Nat::from(5_u64)),
+        };
+        let queued = QueuedNotification {
+            subscriber,
+            notification: DepositNotification {
+                owner,
+                deposit_index: 2,
+                chain_id: 8453,
+                token: Some("0x2222222222222222222222222222222222222222".to_string()),
+                amount: Nat::from(1_000_u64),
+                tx_hash: Some("0x123".to_string()),
+                block_number: 100,
+                block_hash: "0xabc".to_string(),
+                status: DepositStatus::Confirmed,
+            },
+            attempts: 2,
+            next_attempt_at: 42,
+        };
+        // Generator point of secp256k1, a valid public key.
+        let public_key = EcdsaPublicKeyResponse {
+            public_key: alloy_primitives::hex::decode(
+                "0279be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798",
+            )
+            .unwrap(),
+            chain_code: vec![7; 32],
+        };
+        let state = State {
+            ethereum_network: EthereumNetwork::Mainnet,
+            ecdsa_key_name: EcdsaKeyName::TestKey1,
+            ecdsa_public_key: Some(EcdsaPublicKey::from(public_key)),
+            max_fee_per_gas_ceiling: Some(100_000_000_000),
+            gas_limit_buffer_percent: Some(25),
+            rpc_providers: None,
+            custom_rpc_services: Some(vec![rpc_api]),
+            consensus_strategy: Some(ConsensusStrategy::Threshold { min: 1 }),
+            broadcast_strategy: Some(BroadcastStrategy::AllAtOnce),
+            additional_chains: BTreeMap::from([(8453, additional_chain)]),
+            evm_rpc_cycles: Some(3_000_000_000),
+            fee_bump_after_blocks: Some(10),
+            max_fee_bumps: Some(4),
+            transactions: BTreeMap::from([("0xaaa".to_string(), sent_transaction)]),
+            deposit_confirmations: Some(20),
+            finality_tag: Some(FinalityTag::Safe),
+            deposit_accounts: BTreeMap::from([((8453, owner), deposit_account)]),
+            deposit_scanned_blocks: BTreeMap::from([(8453, 120)]),
+            pending_deposits: BTreeSet::from([(owner, 2)]),
+            subscriptions: BTreeMap::from([(subscriber, "on_deposit".to_string())]),
+            notifications: BTreeMap::from([(9, queued)]),
+            next_notification_id: 12,
+            ..Default::default()
+        };
+
+        let bytes = StableState::from(&state).to_bytes().into_owned();
+        let restored = State::from(StableState::from_bytes(Cow::Owned(bytes)));
+
+        assert_eq!(restored, state);
+    }
+}
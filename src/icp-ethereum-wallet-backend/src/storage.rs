@@ -0,0 +1,67 @@
+use ic_stable_structures::memory_manager::{MemoryId, MemoryManager, VirtualMemory};
+use ic_stable_structures::DefaultMemoryImpl;
+use std::cell::RefCell;
+
+/// Memory holding the serialized `State` across upgrades.
+const STATE_MEMORY_ID: MemoryId = MemoryId::new(0);
+
+/// Memory holding the transaction history of each principal.
+const HISTORY_MEMORY_ID: MemoryId = MemoryId::new(1);
+
+/// Memory holding the deposits credited to each principal.
+const DEPOSITS_MEMORY_ID: MemoryId = MemoryId::new(2);
+
+/// Memory holding the position in the history of transactions no longer tracked in the state.
+const HISTORY_INDEX_MEMORY_ID: MemoryId = MemoryId::new(3);
+
+/// Memory holding the length of the transaction history of each principal.
+const HISTORY_LENGTHS_MEMORY_ID: MemoryId = MemoryId::new(4);
+
+/// Memory holding the number of deposits of each principal.
+const DEPOSIT_COUNTS_MEMORY_ID: MemoryId = MemoryId::new(5);
+
+/// Memory holding the owner and position of each credited token transfer.
+const CREDITED_TRANSFERS_MEMORY_ID: MemoryId = MemoryId::new(6);
+
+pub type VMem = VirtualMemory<DefaultMemoryImpl>;
+
+thread_local! {
+    /// Splits the canister's stable memory into independent virtual memories.
+    static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+}
+
+/// Return the virtual memory in which the state is saved before an upgrade.
+pub fn state_memory() -> VMem {
+    MEMORY_MANAGER.with(|m| m.borrow().get(STATE_MEMORY_ID))
+}
+
+/// Return the virtual memory in which the transaction history is stored.
+pub fn history_memory() -> VMem {
+    MEMORY_MANAGER.with(|m| m.borrow().get(HISTORY_MEMORY_ID))
+}
+
+/// Return the virtual memory in which the deposits are stored.
+pub fn deposits_memory() -> VMem {
+    MEMORY_MANAGER.with(|m| m.borrow().get(DEPOSITS_MEMORY_ID))
+}
+
+/// Return the virtual memory in which the history is indexed by transaction hash.
+pub fn history_index_memory() -> VMem {
+    MEMORY_MANAGER.with(|m| m.borrow().get(HISTORY_INDEX_MEMORY_ID))
+}
+
+/// Return the virtual memory in which the length of each history is stored.
+pub fn history_lengths_memory() -> VMem {
+    MEMORY_MANAGER.with(|m| m.borrow().get(HISTORY_LENGTHS_MEMORY_ID))
+}
+
+/// Return the virtual memory in which the number of deposits of each principal is stored.
+pub fn deposit_counts_memory() -> VMem {
+    MEMORY_MANAGER.with(|m| m.borrow().get(DEPOSIT_COUNTS_MEMORY_ID))
+}
+
+/// Return the virtual memory in which the deposits are indexed by token transfer.
+pub fn credited_transfers_memory() -> VMem {
+    MEMORY_MANAGER.with(|m| m.borrow().get(CREDITED_TRANSFERS_MEMORY_ID))
+}